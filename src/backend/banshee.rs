@@ -1,31 +1,40 @@
-use std::default::Default;
-
 use worker::{ValueCount, TimeData, CountData, GaugeData};
-use backend::BackEnd;
-use ::CONFIG;
+use backend::{BackEnd, Transport};
+use config::Config;
 
 pub struct Banshee {
     allow_time: Vec<String>,
     prefix_counter: String,
     prefix_timer: String,
     prefix_gauge: String,
+    address: String,
     validate: bool,
 }
 
-impl Default for Banshee {
-    fn default() -> Self {
+impl Banshee {
+    pub fn new(config: &Config) -> Banshee {
+        let cfg = &config.banshee;
         Banshee {
-            allow_time: CONFIG.banshee.allow.clone(),
+            allow_time: cfg.allow.clone(),
             prefix_counter: "counter".to_owned(),
             prefix_timer: "timer".to_owned(),
             prefix_gauge: "gauge".to_owned(),
+            address: cfg.address.clone(),
             // always validate
-            validate: CONFIG.banshee.validate,
+            validate: cfg.validate,
         }
     }
 }
 
 impl BackEnd for Banshee {
+    fn name(&self) -> &'static str {
+        "banshee"
+    }
+
+    fn transport(&self) -> Transport {
+        Transport::Tcp(self.address.clone())
+    }
+
     fn validate(&self) -> bool {
         debug!("is validate backend for banshee: {}", self.validate);
         self.validate
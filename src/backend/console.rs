@@ -0,0 +1,201 @@
+use std::collections::BTreeMap;
+use std::io::Write;
+
+use serde_json;
+
+use worker::{ValueCount, TimeData, CountData, GaugeData, LightBuffer};
+use backend::{BackEnd, Transport};
+use config::Config;
+
+/// Console pretty prints every flushed buffer to stdout for debugging.
+pub struct Console {
+    json: bool,
+    counter: bool,
+    gauge: bool,
+    timer: bool,
+    validate: bool,
+}
+
+#[derive(Serialize)]
+struct Flush<'a> {
+    timestamp: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    counters: Option<BTreeMap<&'a str, Counter>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    gauges: Option<BTreeMap<&'a str, f64>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    timers: Option<BTreeMap<&'a str, BTreeMap<&'a str, f64>>>,
+}
+
+#[derive(Serialize)]
+struct Counter {
+    value: f64,
+    count: f64,
+}
+
+impl Console {
+    pub fn new(config: &Config) -> Console {
+        let cfg = &config.console;
+        let allow = |kind: &str| cfg.types.is_empty() || cfg.types.iter().any(|x| x == kind);
+        Console {
+            json: cfg.format == "json",
+            counter: allow("counter"),
+            gauge: allow("gauge"),
+            timer: allow("timer"),
+            validate: cfg.validate,
+        }
+    }
+
+    fn to_json(&self, light: &LightBuffer) -> Vec<u8> {
+        let counters = light.count
+            .iter()
+            .map(|(key, &ValueCount(value, count))| (&key[..], Counter { value, count }))
+            .collect();
+        let gauges = light.gauge.iter().map(|(key, &value)| (&key[..], value)).collect();
+        let timers = light.time
+            .iter()
+            .map(|(key, submap)| {
+                (&key[..], submap.iter().map(|(sub, &value)| (&sub[..], value)).collect())
+            })
+            .collect();
+        let flush = Flush {
+            timestamp: light.timestamp,
+            counters: if self.counter { Some(counters) } else { None },
+            gauges: if self.gauge { Some(gauges) } else { None },
+            timers: if self.timer { Some(timers) } else { None },
+        };
+        // a nan or inf value is written as null
+        match serde_json::to_vec_pretty(&flush) {
+            Ok(mut buffer) => {
+                buffer.push(b'\n');
+                buffer
+            }
+            Err(err) => {
+                error!("fail to print the flush at {} as json, error: {}", light.timestamp, err);
+                Vec::new()
+            }
+        }
+    }
+}
+
+impl BackEnd for Console {
+    fn name(&self) -> &'static str {
+        "console"
+    }
+
+    fn transport(&self) -> Transport {
+        Transport::Stdout
+    }
+
+    fn validate(&self) -> bool {
+        self.validate
+    }
+
+    fn counting(&self, _ts: u64, count: &CountData, buf: &mut Vec<u8>) {
+        let sorted: BTreeMap<_, _> = count.iter().collect();
+        for (key, &ValueCount(value, count)) in sorted {
+            let _ = writeln!(buf, "  counter {} value={} count={}", key, value, count);
+        }
+    }
+
+    fn gauging(&self, _ts: u64, gauge: &GaugeData, buf: &mut Vec<u8>) {
+        let sorted: BTreeMap<_, _> = gauge.iter().collect();
+        for (key, value) in sorted {
+            let _ = writeln!(buf, "  gauge {} value={}", key, value);
+        }
+    }
+
+    fn timing(&self, _ts: u64, time: &TimeData, buf: &mut Vec<u8>) {
+        let sorted: BTreeMap<_, _> = time.iter().collect();
+        for (key, submap) in sorted {
+            let subs: BTreeMap<_, _> = submap.iter().collect();
+            let fields: Vec<_> = subs.iter().map(|(sub, value)| format!("{}={}", sub, value)).collect();
+            let _ = writeln!(buf, "  timer {} {}", key, fields.join(" "));
+        }
+    }
+
+    fn apply(&mut self, light: &LightBuffer) -> Vec<u8> {
+        if self.json {
+            return self.to_json(light);
+        }
+        let ts = light.timestamp;
+        let mut buffer = format!("flush at {}\n", ts).into_bytes();
+        if self.counter {
+            self.counting(ts, &light.count, &mut buffer);
+        }
+        if self.gauge {
+            self.gauging(ts, &light.gauge, &mut buffer);
+        }
+        if self.timer {
+            self.timing(ts, &light.time, &mut buffer);
+        }
+        buffer
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::collections::HashMap;
+    use std::f64;
+
+    fn light() -> LightBuffer {
+        let mut count = HashMap::new();
+        count.insert("c".to_owned(), ValueCount(2.0, 20.0));
+        let mut gauge = HashMap::new();
+        gauge.insert("g".to_owned(), f64::NAN);
+        let mut time = HashMap::new();
+        let mut submap = HashMap::new();
+        submap.insert("upper".to_owned(), 3.0);
+        submap.insert("mean".to_owned(), f64::INFINITY);
+        time.insert("t".to_owned(), submap);
+        LightBuffer {
+            timestamp: 10,
+            gauge,
+            count,
+            time,
+            samples: HashMap::new(),
+            sketches: HashMap::new(),
+        }
+    }
+
+    fn console(format: &str, types: &[&str]) -> Console {
+        let mut config = Config::default();
+        config.console.format = format.to_owned();
+        config.console.types = types.iter().map(|x| x.to_string()).collect();
+        Console::new(&config)
+    }
+
+    #[test]
+    fn test_text_format() {
+        let buf = console("text", &[]).apply(&light());
+        assert_eq!(String::from_utf8(buf).unwrap(),
+                   "flush at 10\n  counter c value=2 count=20\n  gauge g value=NaN\n  \
+                    timer t mean=inf upper=3\n");
+    }
+
+    #[test]
+    fn test_json_format() {
+        let buf = console("json", &[]).apply(&light());
+        let flush: serde_json::Value = serde_json::from_slice(&buf).unwrap();
+        assert_eq!(flush["timestamp"], 10);
+        assert_eq!(flush["counters"]["c"]["value"], 2.0);
+        assert_eq!(flush["counters"]["c"]["count"], 20.0);
+        // a non-finite value is null instead of dropping the whole flush
+        assert!(flush["gauges"]["g"].is_null());
+        assert!(flush["timers"]["t"]["mean"].is_null());
+        assert_eq!(flush["timers"]["t"]["upper"], 3.0);
+    }
+
+    #[test]
+    fn test_types_filter() {
+        let text = String::from_utf8(console("text", &["gauge"]).apply(&light())).unwrap();
+        assert_eq!(text, "flush at 10\n  gauge g value=NaN\n");
+
+        let buf = console("json", &["counter", "timer"]).apply(&light());
+        let flush: serde_json::Value = serde_json::from_slice(&buf).unwrap();
+        assert!(flush.get("gauges").is_none());
+        assert!(flush.get("counters").is_some());
+        assert!(flush.get("timers").is_some());
+    }
+}
@@ -0,0 +1,213 @@
+use std::io::Write;
+
+use worker::{ValueCount, TimeData, TimeMap, TimeSet, SketchMap, CountData, GaugeData,
+             LightBuffer, encode_samples};
+use backend::{BackEnd, Transport, MAX_UDP_PAYLOAD};
+use config::Config;
+
+/// Forward re-encodes the aggregated buffer as statsd lines and sends
+/// them to an upstream statsd, which makes a local/global two tier
+/// aggregation.
+pub struct Forward {
+    transport: Transport,
+    timer: TimerMode,
+    validate: bool,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum TimerMode {
+    /// send every sample as `|ms` with the sample rate keeping the count
+    Raw,
+    /// send the caculated timer stats as gauges
+    Stats,
+    /// send the samples and sketches as they are, the upstream merges them
+    /// into its own timers
+    Merge,
+}
+
+impl Forward {
+    pub fn new(config: &Config) -> Forward {
+        let cfg = &config.forward;
+        let timer = match &cfg.timer[..] {
+            "raw" => TimerMode::Raw,
+            "stats" => TimerMode::Stats,
+            "merge" => TimerMode::Merge,
+            other => {
+                warn!("unknown forward timer mode {}, use raw instead", other);
+                TimerMode::Raw
+            }
+        };
+        let transport = match &cfg.protocol[..] {
+            "tcp" => Transport::Tcp(cfg.address.clone()),
+            _ => Transport::Udp(cfg.address.clone()),
+        };
+        Forward {
+            transport,
+            timer,
+            validate: cfg.validate,
+        }
+    }
+
+    fn sampling(&self, samples: &TimeMap, buf: &mut Vec<u8>) {
+        for (key, &TimeSet(ref values, count, _, ref weights)) in samples {
+            if values.is_empty() {
+                continue;
+            }
+            // a weighted sample stands for its share of the count
+            let total = if weights.is_empty() {
+                values.len() as f64
+            } else {
+                weights.iter().sum()
+            };
+            for (idx, value) in values.iter().enumerate() {
+                let weight = weights.get(idx).cloned().unwrap_or(1.0);
+                write_sample(buf, key, *value, total / (count * weight));
+            }
+        }
+    }
+
+    /// the points of a sketch are sent as the samples they stand for, so
+    /// the upstream keeps the count.
+    fn sketching(&self, sketches: &SketchMap, buf: &mut Vec<u8>) {
+        for (key, sketch) in sketches {
+            for (value, weight) in sketch.points() {
+                write_sample(buf, key, value, sketch.len / (sketch.count * weight));
+            }
+        }
+    }
+}
+
+/// the timers as the lines of the merge export, each fits in a datagram.
+fn merging(light: &LightBuffer, buf: &mut Vec<u8>) {
+    for (key, set) in &light.samples {
+        if set.0.is_empty() {
+            continue;
+        }
+        for payload in encode_samples(set, MAX_UDP_PAYLOAD.saturating_sub(key.len() + 2)) {
+            let _ = writeln!(buf, "{}:{}", key, payload);
+        }
+    }
+    for (key, sketch) in &light.sketches {
+        for payload in sketch.encode(MAX_UDP_PAYLOAD.saturating_sub(key.len() + 2)) {
+            let _ = writeln!(buf, "{}:{}", key, payload);
+        }
+    }
+}
+
+/// a rate above 1, eg: of a count below the samples, is sent as 1 since
+/// the upstream drops it.
+fn write_sample(buf: &mut Vec<u8>, key: &str, value: f64, rate: f64) {
+    let _ = if rate >= 1.0 {
+        writeln!(buf, "{}:{}|ms", key, value)
+    } else {
+        writeln!(buf, "{}:{}|ms|@{}", key, value, rate)
+    };
+}
+
+impl BackEnd for Forward {
+    fn name(&self) -> &'static str {
+        "forward"
+    }
+
+    fn transport(&self) -> Transport {
+        self.transport.clone()
+    }
+
+    fn validate(&self) -> bool {
+        self.validate
+    }
+
+    fn counting(&self, _ts: u64, count: &CountData, buf: &mut Vec<u8>) {
+        for (key, &ValueCount(value, _count)) in count {
+            let _ = writeln!(buf, "{}:{}|c", key, value);
+        }
+    }
+
+    fn gauging(&self, _ts: u64, gauge: &GaugeData, buf: &mut Vec<u8>) {
+        for (key, value) in gauge {
+            let _ = writeln!(buf, "{}:{}|g", key, value);
+        }
+    }
+
+    fn timing(&self, _ts: u64, time: &TimeData, buf: &mut Vec<u8>) {
+        for (key, submap) in time {
+            for (sub_key, value) in submap {
+                let _ = writeln!(buf, "{}.{}:{}|g", key, sub_key, value);
+            }
+        }
+    }
+
+    fn apply(&mut self, light: &LightBuffer) -> Vec<u8> {
+        let mut buffer = Vec::new();
+        let ts = light.timestamp;
+        self.counting(ts, &light.count, &mut buffer);
+        self.gauging(ts, &light.gauge, &mut buffer);
+        match self.timer {
+            TimerMode::Raw => {
+                self.sampling(&light.samples, &mut buffer);
+                self.sketching(&light.sketches, &mut buffer);
+            }
+            TimerMode::Stats => self.timing(ts, &light.time, &mut buffer),
+            TimerMode::Merge => merging(light, &mut buffer),
+        }
+        buffer
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use worker::{Line, MergeBuffer};
+
+    #[test]
+    fn test_forward_raw_samples_keep_count() {
+        let forward = Forward {
+            transport: Transport::Udp("127.0.0.1:8125".to_owned()),
+            timer: TimerMode::Raw,
+            validate: true,
+        };
+        let mut samples = TimeMap::new();
+        samples.insert("api".to_owned(), TimeSet(vec![1.0, 2.0], 4.0, 2, Vec::new()));
+
+        let mut buf = Vec::new();
+        forward.sampling(&samples, &mut buf);
+        assert_eq!(String::from_utf8(buf).unwrap(),
+                   "api:1|ms|@0.5\napi:2|ms|@0.5\n");
+
+        // a count below the samples never makes a rate above 1
+        samples.insert("api".to_owned(), TimeSet(vec![5.0, 6.0], 1.0, 2, Vec::new()));
+        let mut buf = Vec::new();
+        forward.sampling(&samples, &mut buf);
+        for line in buf.split(|&byte| byte == b'\n').filter(|line| !line.is_empty()) {
+            assert!(Line::parse(line).is_ok(), "{}", String::from_utf8_lossy(line));
+        }
+    }
+
+    #[test]
+    fn test_forward_merge_global_percentiles() {
+        for mode in &["exact", "tdigest", "ddsketch"] {
+            let config = Config { timer_mode: mode.to_string(), ..Config::default() };
+            let upstream = MergeBuffer::new(1, &config);
+            // node a sees 1..1000 and node b 1001..2000
+            for node in 0..2 {
+                let buf = MergeBuffer::new(1, &config);
+                for num in 1..1001 {
+                    let line = format!("api:{}|ms", node * 1000 + num);
+                    buf.push(0, Line::parse(line.as_bytes()).unwrap());
+                }
+                let mut lines = Vec::new();
+                merging(&buf.truncate(10, &config), &mut lines);
+                for line in lines.split(|&byte| byte == b'\n').filter(|line| !line.is_empty()) {
+                    assert!(line.len() <= MAX_UDP_PAYLOAD);
+                    upstream.push(0, Line::parse(line).unwrap());
+                }
+            }
+            let light = upstream.truncate(10, &config);
+            let stats = &light.time["api"];
+            assert_eq!((stats["count"], stats["sum"]), (2000.0, 2001000.0));
+            // not the 1400 of averaging the p90 of the nodes
+            let error = (stats["upper_90"] - 1800.0).abs() / 1800.0;
+            assert!(error < 0.02, "{} upper_90 is {}", mode, stats["upper_90"]);
+        }
+    }
+}
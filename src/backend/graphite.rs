@@ -1,70 +1,172 @@
-use std::default::Default;
+use worker::{ValueCount, TimeData, CountData, GaugeData, LightBuffer};
+use backend::{BackEnd, Transport};
+use config::Config;
 
-use worker::{ValueCount, TimeData, CountData, GaugeData};
-use backend::BackEnd;
-use ::CONFIG;
+/// max data points in one pickle message, the same as carbon's default
+const PICKLE_CHUNK: usize = 500;
 
 pub struct Graphite {
     prefix_counter: String,
     prefix_stats_count: String,
     prefix_timer: String,
     prefix_gauge: String,
+    address: String,
+    pickle: bool,
     validate: bool,
 }
 
-impl Default for Graphite {
-    fn default() -> Self {
+impl Graphite {
+    pub fn new(config: &Config) -> Graphite {
+        let cfg = &config.graphite;
+        let pickle = match &cfg.protocol[..] {
+            "pickle" => true,
+            "plaintext" => false,
+            other => {
+                warn!("unknown graphite protocol {}, use plaintext instead", other);
+                false
+            }
+        };
         Graphite {
             prefix_counter: "stats".to_owned(),
             prefix_stats_count: "stats_counts".to_owned(),
             prefix_timer: "stats.timers".to_owned(),
             prefix_gauge: "stats.gauges".to_owned(),
-            validate: CONFIG.graphite.validate,
+            address: cfg.address.clone(),
+            pickle,
+            validate: cfg.validate,
+        }
+    }
+
+    fn count_points<F: FnMut(String, f64)>(&self, count: &CountData, mut f: F) {
+        for (key, &ValueCount(v, c)) in count {
+            // count rate
+            f(format!("{}.{}", self.prefix_counter, key), v);
+            f(format!("{}.{}", self.prefix_stats_count, key), c);
+        }
+    }
+
+    fn gauge_points<F: FnMut(String, f64)>(&self, gauge: &GaugeData, mut f: F) {
+        for (key, &val) in gauge {
+            f(format!("{}.{}", self.prefix_gauge, key), val);
+        }
+    }
+
+    fn time_points<F: FnMut(String, f64)>(&self, time: &TimeData, mut f: F) {
+        for (thekey, submap) in time {
+            for (subkey, &val) in submap {
+                f(format!("{}.{}.{}", self.prefix_timer, thekey, subkey), val);
+            }
         }
     }
 }
 
 impl BackEnd for Graphite {
+    fn name(&self) -> &'static str {
+        "graphite"
+    }
+
+    fn transport(&self) -> Transport {
+        Transport::Tcp(self.address.clone())
+    }
+
     fn validate(&self) -> bool {
         self.validate
     }
 
     fn counting(&self, ts: u64, count: &CountData, buffer: &mut Vec<u8>) {
-        let iter = count.into_iter()
-            .map(|(key, &ValueCount(v, c))| {
-                format!("{pc}.{key} {val} {ts}\n{psc}.{key} {count} {ts}\n",
-                        pc = self.prefix_counter,
-                        psc = self.prefix_stats_count,
-                        key = key,
-                        ts = ts,
-                        // count rate
-                        val = v,
-                        count = c)
-            });
-        for dline in iter {
-            buffer.extend_from_slice(dline.as_bytes());
-        }
+        self.count_points(count, |path, val| plaintext(&path, ts, val, buffer));
     }
 
     fn gauging(&self, ts: u64, gauge: &GaugeData, buffer: &mut Vec<u8>) {
-        let iter = gauge.into_iter()
-            .map(|(key, val)| format!("{}.{} {} {}\n", self.prefix_gauge, key, val, ts));
-        for line in iter {
-            buffer.extend_from_slice(line.as_bytes());
-        }
+        self.gauge_points(gauge, |path, val| plaintext(&path, ts, val, buffer));
     }
 
     fn timing(&self, ts: u64, time: &TimeData, buffer: &mut Vec<u8>) {
-        for (thekey, submap) in time.into_iter() {
-            for (subkey, val) in submap.into_iter() {
-                let line = format!("{}.{}.{} {} {}\n",
-                                   self.prefix_timer,
-                                   thekey,
-                                   subkey,
-                                   val,
-                                   ts);
-                buffer.extend_from_slice(line.as_bytes());
-            }
+        self.time_points(time, |path, val| plaintext(&path, ts, val, buffer));
+    }
+
+    fn apply(&mut self, light: &LightBuffer) -> Vec<u8> {
+        let mut buffer = Vec::new();
+        let ts = light.timestamp;
+        if !self.pickle {
+            self.counting(ts, &light.count, &mut buffer);
+            self.gauging(ts, &light.gauge, &mut buffer);
+            self.timing(ts, &light.time, &mut buffer);
+            return buffer;
+        }
+
+        let mut points = Vec::new();
+        {
+            let mut push = |path, val| points.push((path, val));
+            self.count_points(&light.count, &mut push);
+            self.gauge_points(&light.gauge, &mut push);
+            self.time_points(&light.time, &mut push);
+        }
+        for chunk in points.chunks(PICKLE_CHUNK) {
+            pickle(chunk, ts, &mut buffer);
         }
+        buffer
+    }
+}
+
+fn plaintext(path: &str, ts: u64, val: f64, buffer: &mut Vec<u8>) {
+    let line = format!("{} {} {}\n", path, val, ts);
+    buffer.extend_from_slice(line.as_bytes());
+}
+
+/// Encode the points as one length prefixed pickle (protocol 2) message of
+/// `[(path, (timestamp, value)), ...]`, which carbon's pickle receiver reads.
+fn pickle(points: &[(String, f64)], ts: u64, buffer: &mut Vec<u8>) {
+    const PROTO: u8 = 0x80;
+    const EMPTY_LIST: u8 = b']';
+    const MARK: u8 = b'(';
+    const BINUNICODE: u8 = b'X';
+    const BININT: u8 = b'J';
+    const LONG1: u8 = 0x8a;
+    const BINFLOAT: u8 = b'G';
+    const TUPLE2: u8 = 0x86;
+    const APPENDS: u8 = b'e';
+    const STOP: u8 = b'.';
+
+    let mut payload = vec![PROTO, 2, EMPTY_LIST, MARK];
+    for &(ref path, val) in points {
+        payload.push(BINUNICODE);
+        payload.extend_from_slice(&(path.len() as u32).to_le_bytes());
+        payload.extend_from_slice(path.as_bytes());
+        if ts <= i32::MAX as u64 {
+            payload.push(BININT);
+            payload.extend_from_slice(&(ts as i32).to_le_bytes());
+        } else {
+            payload.push(LONG1);
+            payload.push(8);
+            payload.extend_from_slice(&(ts as i64).to_le_bytes());
+        }
+        payload.push(BINFLOAT);
+        payload.extend_from_slice(&val.to_bits().to_be_bytes());
+        payload.push(TUPLE2);
+        payload.push(TUPLE2);
+    }
+    payload.push(APPENDS);
+    payload.push(STOP);
+
+    buffer.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    buffer.extend_from_slice(&payload);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_pickle_message() {
+        let mut buf = Vec::new();
+        pickle(&[("a.b".to_owned(), 1.5)], 10, &mut buf);
+        let payload: &[u8] = &[0x80, 2, b']', b'(',
+                               b'X', 3, 0, 0, 0, b'a', b'.', b'b',
+                               b'J', 10, 0, 0, 0,
+                               b'G', 0x3f, 0xf8, 0, 0, 0, 0, 0, 0,
+                               0x86, 0x86, b'e', b'.'];
+        assert_eq!(&buf[..4], &(payload.len() as u32).to_be_bytes()[..]);
+        assert_eq!(&buf[4..], payload);
     }
 }
@@ -0,0 +1,203 @@
+use worker::{ValueCount, TimeData, CountData, GaugeData};
+use backend::{BackEnd, Transport};
+use config::Config;
+
+/// Render metrics as InfluxDB line protocol:
+/// `measurement,metric_type=<type> field=value[,field=value] timestamp`
+pub struct Influxdb {
+    transport: Transport,
+    precision: Precision,
+    validate: bool,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Precision {
+    Nanosecond,
+    Microsecond,
+    Millisecond,
+    Second,
+    Minute,
+    Hour,
+}
+
+impl Precision {
+    fn parse(precision: &str) -> Option<Precision> {
+        match precision {
+            "n" | "ns" => Some(Precision::Nanosecond),
+            "u" | "us" => Some(Precision::Microsecond),
+            "ms" => Some(Precision::Millisecond),
+            "s" => Some(Precision::Second),
+            "m" => Some(Precision::Minute),
+            "h" => Some(Precision::Hour),
+            _ => None,
+        }
+    }
+
+    fn query(&self) -> &'static str {
+        match *self {
+            Precision::Nanosecond => "n",
+            Precision::Microsecond => "u",
+            Precision::Millisecond => "ms",
+            Precision::Second => "s",
+            Precision::Minute => "m",
+            Precision::Hour => "h",
+        }
+    }
+
+    /// convert a timestamp in seconds into the precision
+    fn convert(&self, ts: u64) -> u64 {
+        match *self {
+            Precision::Nanosecond => ts * 1_000_000_000,
+            Precision::Microsecond => ts * 1_000_000,
+            Precision::Millisecond => ts * 1_000,
+            Precision::Second => ts,
+            Precision::Minute => ts / 60,
+            Precision::Hour => ts / 3600,
+        }
+    }
+}
+
+impl Influxdb {
+    pub fn new(config: &Config) -> Influxdb {
+        let cfg = &config.influxdb;
+        let precision = Precision::parse(&cfg.precision).unwrap_or_else(|| {
+            warn!("unknown influxdb precision {}, use s instead", cfg.precision);
+            Precision::Second
+        });
+        let path = format!("{}?db={}&precision={}",
+                           cfg.path,
+                           url_encode(&cfg.database),
+                           precision.query());
+        let transport = Transport::parse(&cfg.protocol, &cfg.address, &path).unwrap_or_else(|| {
+            warn!("unknown influxdb protocol {}, use udp instead", cfg.protocol);
+            Transport::Udp(cfg.address.clone())
+        });
+        Influxdb {
+            transport,
+            precision,
+            validate: cfg.validate,
+        }
+    }
+
+    fn write_line(&self,
+                  key: &str,
+                  kind: &str,
+                  fields: &[(&str, f64)],
+                  ts: u64,
+                  buf: &mut Vec<u8>) {
+        let fields: Vec<_> = fields.iter()
+            .filter(|&&(_, val)| val.is_finite())
+            .map(|&(name, val)| format!("{}={}", escape(name, false), val))
+            .collect();
+        if fields.is_empty() {
+            return;
+        }
+        let line = format!("{},metric_type={} {} {}\n",
+                           escape(key, true),
+                           kind,
+                           fields.join(","),
+                           self.precision.convert(ts));
+        buf.extend_from_slice(line.as_bytes());
+    }
+}
+
+impl BackEnd for Influxdb {
+    fn name(&self) -> &'static str {
+        "influxdb"
+    }
+
+    fn transport(&self) -> Transport {
+        self.transport.clone()
+    }
+
+    fn validate(&self) -> bool {
+        self.validate
+    }
+
+    fn counting(&self, ts: u64, count: &CountData, buf: &mut Vec<u8>) {
+        for (key, &ValueCount(value, count)) in count {
+            self.write_line(key, "counter", &[("value", value), ("count", count)], ts, buf);
+        }
+    }
+
+    fn gauging(&self, ts: u64, gauge: &GaugeData, buf: &mut Vec<u8>) {
+        for (key, &value) in gauge {
+            self.write_line(key, "gauge", &[("value", value)], ts, buf);
+        }
+    }
+
+    fn timing(&self, ts: u64, time: &TimeData, buf: &mut Vec<u8>) {
+        for (key, submap) in time {
+            let mut fields: Vec<_> = submap.iter().map(|(sub, &val)| (&sub[..], val)).collect();
+            // keep the field order stable between flushes
+            fields.sort_by(|x, y| x.0.cmp(y.0));
+            self.write_line(key, "timer", &fields, ts, buf);
+        }
+    }
+}
+
+/// escape measurement (commas and spaces) or field keys (also equal signs)
+fn escape(input: &str, measurement: bool) -> String {
+    let mut output = String::with_capacity(input.len());
+    for ch in input.chars() {
+        match ch {
+            ',' | ' ' => output.push('\\'),
+            '=' if !measurement => output.push('\\'),
+            _ => {}
+        }
+        output.push(ch);
+    }
+    output
+}
+
+fn url_encode(input: &str) -> String {
+    let mut output = String::with_capacity(input.len());
+    for &byte in input.as_bytes() {
+        match byte {
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                output.push(byte as char)
+            }
+            _ => output.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    output
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn influxdb() -> Influxdb {
+        Influxdb {
+            transport: Transport::Udp("127.0.0.1:8089".to_owned()),
+            precision: Precision::Millisecond,
+            validate: true,
+        }
+    }
+
+    #[test]
+    fn test_influxdb_timer_as_one_measurement() {
+        let mut sub = HashMap::new();
+        sub.insert("upper".to_owned(), 10.0);
+        sub.insert("mean_90".to_owned(), 4.5);
+        let mut time = TimeData::new();
+        time.insert("api latency,v1".to_owned(), sub);
+
+        let mut buf = Vec::new();
+        influxdb().timing(10, &time, &mut buf);
+        assert_eq!(String::from_utf8(buf).unwrap(),
+                   "api\\ latency\\,v1,metric_type=timer mean_90=4.5,upper=10 10000\n");
+    }
+
+    #[test]
+    fn test_influxdb_counter() {
+        let mut count = CountData::new();
+        count.insert("hits".to_owned(), ValueCount(20.0, 2.0));
+
+        let mut buf = Vec::new();
+        influxdb().counting(1, &count, &mut buf);
+        assert_eq!(String::from_utf8(buf).unwrap(),
+                   "hits,metric_type=counter value=20,count=2 1000\n");
+    }
+}
@@ -1,21 +1,44 @@
 pub mod graphite;
 pub mod banshee;
+pub mod influxdb;
+pub mod opentsdb;
+pub mod forward;
+pub mod console;
+pub mod repeater;
+pub mod proxy;
 
-use std::io::{Write, ErrorKind, Result, Error};
-use std::net::SocketAddr;
-use std::time::{Instant, Duration};
-use std::thread;
+use std::io::{self, Write, ErrorKind, Result, Error};
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use futures::{Future, future};
-use tokio_core::reactor::Core;
+use futures::future::Either;
+use tokio_core::reactor::{Core, Handle, Timeout};
 use tokio_core::net::TcpStream;
-use worker::{LightBuffer, TimeData, CountData, GaugeData, MergeBuffer};
+use tokio_io::io::{write_all, read_to_end};
+use worker::{LightBuffer, Line, TimeData, CountData, GaugeData, MergeBuffer};
 
 use self::graphite::Graphite;
 use self::banshee::Banshee;
-use ::CONFIG;
+use self::influxdb::Influxdb;
+use self::opentsdb::Opentsdb;
+use self::forward::Forward;
+use self::console::Console;
+use ::SIGNAL_POLL_MS;
+use com;
+use config::SharedConfig;
+use signal::Shutdown;
+
+/// max payload of a single udp datagram sent to backends
+const MAX_UDP_PAYLOAD: usize = 1432;
+/// the time a http backend has to take the request and answer it
+const HTTP_TIMEOUT_MS: u64 = 5000;
 
 pub trait BackEnd {
+    fn name(&self) -> &'static str;
+    fn transport(&self) -> Transport;
+
     fn counting(&self, ts: u64, count: &CountData, buf: &mut Vec<u8>);
     fn gauging(&self, ts: u64, gauge: &GaugeData, buf: &mut Vec<u8>);
     fn timing(&self, ts: u64, time: &TimeData, buf: &mut Vec<u8>);
@@ -38,93 +61,221 @@ pub trait BackEnd {
     }
 }
 
-pub struct BackEndSender {
-    banshee: Banshee,
-    graphite: Graphite,
+/// The way a rendered buffer is delivered to the backend.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Transport {
+    Tcp(String),
+    /// Udp(address), the buffer is split into datagrams at line boundary
+    Udp(String),
+    /// Http(address, path), the buffer is sent as the body of a POST
+    Http(String, String),
+    Stdout,
 }
 
-impl Default for BackEndSender {
-    fn default() -> Self {
-        BackEndSender {
-            banshee: Banshee::default(),
-            graphite: Graphite::default(),
+impl Transport {
+    pub fn parse(protocol: &str, address: &str, path: &str) -> Option<Transport> {
+        match protocol {
+            "tcp" => Some(Transport::Tcp(address.to_owned())),
+            "udp" => Some(Transport::Udp(address.to_owned())),
+            "http" => Some(Transport::Http(address.to_owned(), path.to_owned())),
+            _ => None,
         }
     }
+
+    fn deliver(self, buf: Vec<u8>, handle: &Handle) -> Box<dyn Future<Item = (), Error = Error>> {
+        if buf.is_empty() {
+            debug!("get a zero len of buffer, skip");
+            return Box::new(future::ok(()));
+        }
+        match self {
+            Transport::Tcp(address) => {
+                let handle = handle.clone();
+                let ret = future::result(resolve(&address))
+                    .and_then(move |addr| TcpStream::connect(&addr, &handle))
+                    .and_then(|socket| send_to(socket, buf));
+                Box::new(ret)
+            }
+            Transport::Udp(address) => {
+                Box::new(future::result(resolve(&address).and_then(|addr| send_udp(&addr, &buf))))
+            }
+            Transport::Http(address, path) => {
+                post(&address, &path, &buf, Duration::from_millis(HTTP_TIMEOUT_MS), handle)
+            }
+            Transport::Stdout => {
+                let stdout = io::stdout();
+                let mut handle = stdout.lock();
+                Box::new(future::result(handle.write_all(&buf).and_then(|_| handle.flush())))
+            }
+        }
+    }
+}
+
+pub struct BackEndSender {
+    backends: Vec<Box<dyn BackEnd>>,
+    shared: Arc<SharedConfig>,
+    /// the config generation which the backends are built from
+    generation: usize,
 }
 
 impl BackEndSender {
-    pub fn serve(&mut self, input: &MergeBuffer) {
+    pub fn new(shared: Arc<SharedConfig>) -> BackEndSender {
+        let config = shared.get();
+        BackEndSender {
+            generation: shared.generation(),
+            backends: vec![Box::new(Banshee::new(&config)),
+                           Box::new(Graphite::new(&config)),
+                           Box::new(Influxdb::new(&config)),
+                           Box::new(Opentsdb::new(&config)),
+                           Box::new(Forward::new(&config)),
+                           Box::new(Console::new(&config))],
+            shared,
+        }
+    }
+
+    /// flush the merge buffer of ring slot idx to all the backends, until
+    /// the last flush is done when shutdown is draining.
+    pub fn serve(&mut self, idx: usize, input: &MergeBuffer, shutdown: &Shutdown) {
         let mut core = Core::new().unwrap();
         let handle = core.handle();
 
-        let graphite_addr = &CONFIG.graphite.address.parse::<SocketAddr>();
-        let banshee_addr = &CONFIG.banshee.address.parse::<SocketAddr>();
-
-        let dur = Duration::from_secs(CONFIG.interval);
-        let mut last = Instant::now();
-        // hard code and ugly way to implment it
-        let graphite_validate = self.graphite.validate();
-        let banshee_validate = self.banshee.validate();
+        // the interval can't be reloaded
+        let interval = self.shared.get().interval;
+        // flush at the multiples of interval, so all the ring slots get the
+        // same timestamp for the same period.
+        let mut boundary = com::next_boundary(com::now(), interval);
 
         loop {
-            let now = Instant::now();
-            let ndur = now.duration_since(last);
-            if ndur < dur {
-                thread::sleep(ndur);
+            let target = Duration::from_secs(boundary);
+            let wall = com::since_epoch();
+            if shutdown.is_draining() {
+                let config = self.shared.get();
+                let ts = drain_timestamp(com::now(), boundary, interval);
+                let item = input.truncate(ts, &config);
+                let deadline = Duration::from_secs(config.shutdown_timeout);
+                match core.run(self.flush(&item, &handle, deadline)) {
+                    Ok(_) => info!("adapter {}: the last flush is done", idx),
+                    Err(err) => error!("adapter {}: the last flush failed, error: {}", idx, err),
+                }
+                return;
+            }
+            if wall < target {
+                let wait = com::min(target - wall, Duration::from_millis(SIGNAL_POLL_MS));
+                let timer = Timeout::new(wait, &handle).unwrap();
+                core.run(timer).unwrap();
                 continue;
             }
-            last = now;
-            let item = input.truncate();
-
-            let banshee_buf = self.banshee.apply(&item);
-            let banshee = future::lazy(|| -> Result<()> {
-                    if banshee_validate {
-                        Err(Error::new(ErrorKind::Other, "banshee not validate"))
-                    } else {
-                        Ok(())
-                    }
-                })
-                .and_then(|_| {
-                    banshee_addr.clone().map_err(|_| {
-                        Error::new(ErrorKind::AddrNotAvailable, "socket not connected")
-                    })
-                })
-                .and_then(|addr| TcpStream::connect(&addr, &handle))
-                .and_then(|socket| {
-                    debug!("get a new connection");
-                    send_to(socket, banshee_buf)
-                });
-
-            let graphite_buf = self.graphite.apply(&item);
-            let graphite = future::lazy(|| -> Result<()> {
-                    if graphite_validate {
-                        Err(Error::new(ErrorKind::Other, "graphite not validate"))
-                    } else {
+            let start = Instant::now();
+            let jitter = wall - target;
+            if self.generation != self.shared.generation() {
+                info!("adapter {}: config reloaded, rebuild the backends", idx);
+                *self = BackEndSender::new(self.shared.clone());
+            }
+            let item = input.truncate(boundary, &self.shared.get());
+            let keys = item.count.len() + item.gauge.len() + item.samples.len() +
+                       item.sketches.len();
+
+            // a hanging backend must not hold the flushes of the next intervals
+            let deadline = Duration::from_secs(interval);
+            if let Err(err) = core.run(self.flush(&item, &handle, deadline)) {
+                error!("unknown error when send to backend, error: {}", err);
+            }
+
+            // report to the next flush
+            let latency = start.elapsed();
+            input.report(Line::gauge(&format!("statsd.flush.{}.jitter_ms", idx),
+                                     com::millis(jitter)));
+            input.report(Line::gauge(&format!("statsd.flush.{}.latency_ms", idx),
+                                     com::millis(latency)));
+            input.report(Line::gauge(&format!("statsd.ring.{}.keys", idx), keys as f64));
+
+            boundary += interval;
+            let now = com::now();
+            if boundary < now {
+                warn!("flush is behind the wall clock, skip to the next interval");
+                boundary = com::next_boundary(now, interval);
+            }
+        }
+    }
+
+    /// send the buffer to all the validate backends, the error or timeout
+    /// of each backend is logged without failing the others.
+    fn flush(&mut self,
+             item: &LightBuffer,
+             handle: &Handle,
+             deadline: Duration)
+             -> Box<dyn Future<Item = (), Error = Error>> {
+        let sends: Vec<_> = self.backends
+            .iter_mut()
+            .filter(|backend| backend.validate())
+            .map(|backend| {
+                let name = backend.name();
+                let buf = backend.apply(item);
+                let send = backend.transport().deliver(buf, handle);
+                timeout(send, deadline, handle)
+                    .then(move |ret| -> Result<()> {
+                        if let Err(err) = ret {
+                            error!("fail to send to backend {}, error: {}", name, err);
+                        }
                         Ok(())
-                    }
-                })
-                .and_then(|_| {
-                    graphite_addr.clone().map_err(|_| {
-                        Error::new(ErrorKind::AddrNotAvailable, "socket not connected")
                     })
-                })
-                .and_then(|addr| TcpStream::connect(&addr, &handle))
-                .and_then(|socket| send_to(socket, graphite_buf));
-
-            let service = graphite.join(banshee);
-            match core.run(service) {
-                Ok(_) => {}
-                Err(ref err) if err.kind() == ErrorKind::Other => {
-                    debug!("empty buffer, skip");
-                }
-                Err(err) => {
-                    error!("unknown error when send to backend, error: {}", err);
-                }
-            };
-        }
+            })
+            .collect();
+        Box::new(future::join_all(sends).map(|_| ()))
     }
 }
 
+/// the timestamp of the partial interval flushed at shutdown, the wall clock
+/// instead of the boundary in the future, but after the previous flush.
+fn drain_timestamp(now: u64, boundary: u64, interval: u64) -> u64 {
+    com::min(com::max(now, boundary - interval + 1), boundary)
+}
+
+/// post the buffer to a http backend, which must answer in the timeout.
+fn post(address: &str,
+        path: &str,
+        buf: &[u8],
+        duration: Duration,
+        handle: &Handle)
+        -> Box<dyn Future<Item = (), Error = Error>> {
+    let head = format!("POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: text/plain\r\n\
+                        Content-Length: {}\r\nConnection: close\r\n\r\n",
+                       path,
+                       address,
+                       buf.len());
+    let mut request = head.into_bytes();
+    request.extend_from_slice(buf);
+    let connect_handle = handle.clone();
+    let ret = future::result(resolve(address))
+        .and_then(move |addr| TcpStream::connect(&addr, &connect_handle))
+        .and_then(move |socket| write_all(socket, request))
+        .and_then(|(socket, _)| read_to_end(socket, Vec::new()))
+        .and_then(|(_, resp)| check_http_status(&resp));
+    timeout(ret, duration, handle)
+}
+
+/// fail the future with TimedOut if it is not done in the duration.
+fn timeout<F>(future: F, duration: Duration, handle: &Handle) -> Box<dyn Future<Item = (), Error = Error>>
+    where F: Future<Item = (), Error = Error> + 'static
+{
+    let timer = match Timeout::new(duration, handle) {
+        Ok(timer) => timer,
+        Err(err) => return Box::new(future::err(err)),
+    };
+    let ret = future.select2(timer).then(move |ret| match ret {
+        Ok(Either::A(_)) => Ok(()),
+        Ok(Either::B(_)) => {
+            Err(Error::new(ErrorKind::TimedOut, format!("timeout after {:?}", duration)))
+        }
+        Err(Either::A((err, _))) | Err(Either::B((err, _))) => Err(err),
+    });
+    Box::new(ret)
+}
+
+fn resolve(address: &str) -> Result<SocketAddr> {
+    address.to_socket_addrs()?
+        .next()
+        .ok_or_else(|| Error::new(ErrorKind::AddrNotAvailable, "socket not connected"))
+}
 
 fn send_to(mut socket: TcpStream, buf: Vec<u8>) -> Result<()> {
     debug!("want to write the buffer");
@@ -144,3 +295,121 @@ fn send_to(mut socket: TcpStream, buf: Vec<u8>) -> Result<()> {
         return ret;
     }
 }
+
+/// send lines in datagrams no larger than MAX_UDP_PAYLOAD.
+fn send_udp(addr: &SocketAddr, buf: &[u8]) -> Result<()> {
+    let socket = UdpSocket::bind("0.0.0.0:0")?;
+    for datagram in split_datagrams(buf, MAX_UDP_PAYLOAD) {
+        socket.send_to(datagram, addr)?;
+    }
+    Ok(())
+}
+
+/// split buf at '\n' into chunks of at most max bytes, a single line
+/// longer than max is sent as its own chunk.
+fn split_datagrams(buf: &[u8], max: usize) -> Vec<&[u8]> {
+    let mut chunks = Vec::new();
+    let mut start = 0;
+    let mut end = 0;
+    for (idx, &byte) in buf.iter().enumerate() {
+        if byte != b'\n' {
+            continue;
+        }
+        if idx + 1 - start > max && end > start {
+            chunks.push(&buf[start..end]);
+            start = end;
+        }
+        end = idx + 1;
+    }
+    if buf.len() - start > max && end > start {
+        chunks.push(&buf[start..end]);
+        start = end;
+    }
+    if start < buf.len() {
+        chunks.push(&buf[start..]);
+    }
+    chunks
+}
+
+fn check_http_status(resp: &[u8]) -> Result<()> {
+    let status_line = resp.split(|&x| x == b'\n').next().unwrap_or(&[]);
+    let status_line = String::from_utf8_lossy(status_line);
+    let code = status_line.split_whitespace().nth(1).unwrap_or("");
+    if code.starts_with('2') {
+        Ok(())
+    } else {
+        Err(Error::other(format!("http backend response: {}", status_line.trim())))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_split_datagrams() {
+        let buf = b"aaaa\nbbbb\ncccccccccccc\ndd\n";
+        let chunks = split_datagrams(buf, 10);
+        assert_eq!(chunks,
+                   vec![&b"aaaa\nbbbb\n"[..], &b"cccccccccccc\n"[..], &b"dd\n"[..]]);
+        assert_eq!(split_datagrams(b"a\nb", 10), vec![&b"a\nb"[..]]);
+    }
+
+    #[test]
+    fn test_drain_timestamp() {
+        assert_eq!(drain_timestamp(23, 30, 10), 23);
+        // never the timestamp of the previous flush
+        assert_eq!(drain_timestamp(20, 30, 10), 21);
+        // never in the future
+        assert_eq!(drain_timestamp(31, 30, 10), 30);
+    }
+
+    #[test]
+    fn test_http_timeout() {
+        use std::net::TcpListener;
+        // accept the connection and never answer
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+        let start = Instant::now();
+        let ret = core.run(post(&address, "/write", b"a 1", Duration::from_millis(200), &handle));
+        assert_eq!(ret.unwrap_err().kind(), ErrorKind::TimedOut);
+        assert!(start.elapsed() < Duration::from_secs(2));
+    }
+
+    #[test]
+    fn test_flush_timeout_each_backend() {
+        use std::collections::HashMap;
+        use std::net::TcpListener;
+        use config::Config;
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut config = Config::default();
+        config.influxdb.address = listener.local_addr().unwrap().to_string();
+        config.influxdb.protocol = "http".to_string();
+        config.influxdb.validate = true;
+        let mut sender = BackEndSender::new(Arc::new(SharedConfig::new(config)));
+        let mut gauge = HashMap::new();
+        gauge.insert("foo".to_string(), 1.0);
+        let item = LightBuffer {
+            timestamp: 0,
+            gauge,
+            count: HashMap::new(),
+            time: HashMap::new(),
+            samples: HashMap::new(),
+            sketches: HashMap::new(),
+        };
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+        let start = Instant::now();
+        // the hanging backend is logged, the flush itself is done
+        assert!(core.run(sender.flush(&item, &handle, Duration::from_millis(200))).is_ok());
+        assert!(start.elapsed() < Duration::from_secs(2));
+    }
+
+    #[test]
+    fn test_check_http_status() {
+        assert!(check_http_status(b"HTTP/1.1 204 No Content\r\n\r\n").is_ok());
+        assert!(check_http_status(b"HTTP/1.1 400 Bad Request\r\n\r\n").is_err());
+    }
+}
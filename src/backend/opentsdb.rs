@@ -0,0 +1,113 @@
+use worker::{ValueCount, TimeData, CountData, GaugeData};
+use backend::{BackEnd, Transport};
+use config::Config;
+
+/// Render metrics as OpenTSDB telnet style `put` lines:
+/// `put <metric> <timestamp> <value> <tagk=tagv ...>`
+pub struct Opentsdb {
+    prefix_counter: String,
+    prefix_timer: String,
+    prefix_gauge: String,
+    tags: String,
+    address: String,
+    validate: bool,
+}
+
+impl Opentsdb {
+    pub fn new(config: &Config) -> Opentsdb {
+        let cfg = &config.opentsdb;
+        let mut tags = cfg.tags.clone();
+        if tags.is_empty() {
+            // opentsdb rejects data points without any tag
+            warn!("opentsdb tags is empty, use host=statsd instead");
+            tags.push("host=statsd".to_owned());
+        }
+        Opentsdb {
+            prefix_counter: "stats.counters".to_owned(),
+            prefix_timer: "stats.timers".to_owned(),
+            prefix_gauge: "stats.gauges".to_owned(),
+            tags: tags.join(" "),
+            address: cfg.address.clone(),
+            validate: cfg.validate,
+        }
+    }
+
+    fn put(&self, metric: &str, ts: u64, value: f64, buf: &mut Vec<u8>) {
+        if !value.is_finite() {
+            return;
+        }
+        let line = format!("put {} {} {} {}\n", sanitize(metric), ts, value, self.tags);
+        buf.extend_from_slice(line.as_bytes());
+    }
+}
+
+impl BackEnd for Opentsdb {
+    fn name(&self) -> &'static str {
+        "opentsdb"
+    }
+
+    fn transport(&self) -> Transport {
+        Transport::Tcp(self.address.clone())
+    }
+
+    fn validate(&self) -> bool {
+        self.validate
+    }
+
+    fn counting(&self, ts: u64, count: &CountData, buf: &mut Vec<u8>) {
+        for (key, &ValueCount(value, _count)) in count {
+            self.put(&format!("{}.{}", self.prefix_counter, key), ts, value, buf);
+        }
+    }
+
+    fn gauging(&self, ts: u64, gauge: &GaugeData, buf: &mut Vec<u8>) {
+        for (key, &value) in gauge {
+            self.put(&format!("{}.{}", self.prefix_gauge, key), ts, value, buf);
+        }
+    }
+
+    fn timing(&self, ts: u64, time: &TimeData, buf: &mut Vec<u8>) {
+        for (key, submap) in time {
+            for (sub_key, &value) in submap {
+                self.put(&format!("{}.{}.{}", self.prefix_timer, key, sub_key),
+                         ts,
+                         value,
+                         buf);
+            }
+        }
+    }
+}
+
+/// opentsdb only accepts `a-zA-Z0-9-_./` in metric names
+fn sanitize(metric: &str) -> String {
+    metric.chars()
+        .map(|ch| match ch {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '-' | '_' | '.' | '/' => ch,
+            _ => '_',
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_opentsdb_put_with_tags() {
+        let tsdb = Opentsdb {
+            prefix_counter: "stats.counters".to_owned(),
+            prefix_timer: "stats.timers".to_owned(),
+            prefix_gauge: "stats.gauges".to_owned(),
+            tags: "host=web01".to_owned(),
+            address: "127.0.0.1:4242".to_owned(),
+            validate: true,
+        };
+        let mut gauge = GaugeData::new();
+        gauge.insert("queue size".to_owned(), 3.0);
+
+        let mut buf = Vec::new();
+        tsdb.gauging(100, &gauge, &mut buf);
+        assert_eq!(String::from_utf8(buf).unwrap(),
+                   "put stats.gauges.queue_size 100 3 host=web01\n");
+    }
+}
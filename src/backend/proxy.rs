@@ -0,0 +1,222 @@
+use std::io::{self, ErrorKind};
+use std::net::{TcpStream, UdpSocket};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use worker::{Line, Sink};
+use backend::{resolve, MAX_UDP_PAYLOAD};
+use backend::repeater::Target;
+use config::ProxyConfig;
+use ring::HashRing;
+use signal::Shutdown;
+use ::SIGNAL_POLL_MS;
+
+const CHECK_TIMEOUT_MS: u64 = 500;
+
+/// The downstream nodes of a proxy and their health, shared between the
+/// workers and the health checker.
+pub struct Nodes {
+    addresses: Vec<String>,
+    alive: Vec<AtomicBool>,
+}
+
+impl Nodes {
+    pub fn new(addresses: &[String]) -> Nodes {
+        Nodes {
+            addresses: addresses.to_vec(),
+            alive: addresses.iter().map(|_| AtomicBool::new(true)).collect(),
+        }
+    }
+
+    pub fn is_alive(&self, idx: usize) -> bool {
+        self.alive[idx].load(Ordering::SeqCst)
+    }
+
+    /// check all the nodes every interval until shutdown is stopping, and
+    /// log when a node goes down or comes back.
+    pub fn check(&self, cfg: &ProxyConfig, shutdown: &Shutdown) {
+        let interval = Duration::from_secs(cfg.check_interval);
+        loop {
+            let start = Instant::now();
+            for (idx, address) in self.addresses.iter().enumerate() {
+                let ret = check_node(address, &cfg.check);
+                let was_alive = self.alive[idx].swap(ret.is_ok(), Ordering::SeqCst);
+                match ret {
+                    Err(ref err) if was_alive => {
+                        error!("proxy: node {} is down, error: {}", address, err)
+                    }
+                    Ok(_) if !was_alive => info!("proxy: node {} is back", address),
+                    _ => {}
+                }
+            }
+            while start.elapsed() < interval {
+                if shutdown.is_stopping() {
+                    return;
+                }
+                thread::sleep(Duration::from_millis(SIGNAL_POLL_MS));
+            }
+        }
+    }
+}
+
+/// a tcp check connects to the node, a udp check sends an empty datagram
+/// and fails only if the port is reported unreachable.
+fn check_node(address: &str, check: &str) -> io::Result<()> {
+    let addr = resolve(address)?;
+    let timeout = Duration::from_millis(CHECK_TIMEOUT_MS);
+    if check == "tcp" {
+        return TcpStream::connect_timeout(&addr, timeout).map(|_| ());
+    }
+    let socket = UdpSocket::bind("0.0.0.0:0")?;
+    socket.connect(addr)?;
+    socket.set_read_timeout(Some(timeout))?;
+    socket.send(&[])?;
+    match socket.recv(&mut [0; 1]) {
+        Err(ref err) if err.kind() == ErrorKind::WouldBlock ||
+                        err.kind() == ErrorKind::TimedOut => Ok(()),
+        Err(err) => Err(err),
+        Ok(_) => Ok(()),
+    }
+}
+
+/// Proxy forwards every line received by a worker to one of the nodes by
+/// the consistent hash ring, so a metric always lands on the same node
+/// while it is alive. Each worker owns its own proxy.
+pub struct Proxy {
+    ring: HashRing,
+    nodes: Arc<Nodes>,
+    /// the target and the buffered lines of each node
+    targets: Vec<(Option<Target>, Vec<u8>)>,
+}
+
+impl Proxy {
+    pub fn new(cfg: &ProxyConfig, ring: HashRing, nodes: Arc<Nodes>) -> Proxy {
+        let targets = cfg.nodes
+            .iter()
+            .map(|address| {
+                let target = Target::new(address, &cfg.protocol)
+                    .map_err(|err| error!("proxy: skip node {}, error: {}", address, err))
+                    .ok();
+                (target, Vec::with_capacity(MAX_UDP_PAYLOAD))
+            })
+            .collect();
+        Proxy {
+            ring,
+            nodes,
+            targets,
+        }
+    }
+
+    /// send the buffered lines of node idx, the lines of a node which went
+    /// down since they were buffered are dropped, so a dead node never
+    /// stalls the others with a connect.
+    fn send(&mut self, idx: usize) {
+        let (ref mut target, ref mut buf) = self.targets[idx];
+        if !self.nodes.is_alive(idx) {
+            debug!("proxy: node {} is down, drop {} bytes",
+                   self.nodes.addresses[idx],
+                   buf.len());
+        } else if let Some(ref mut target) = *target {
+            if let Err(err) = target.send(buf) {
+                warn!("proxy: fail to forward lines to node {}, error: {}",
+                      self.nodes.addresses[idx],
+                      err);
+            }
+        }
+        buf.clear();
+    }
+}
+
+impl Sink for Proxy {
+    fn push(&mut self, line: Line) {
+        let idx = {
+            let nodes = &self.nodes;
+            match self.ring.position_alive(line.metric, |idx| nodes.is_alive(idx)) {
+                Some(idx) => idx,
+                None => {
+                    debug!("proxy: all nodes are down, drop {}", line.metric);
+                    return;
+                }
+            }
+        };
+        let line = format!("{}\n", line);
+        if self.targets[idx].1.len() + line.len() > MAX_UDP_PAYLOAD {
+            self.send(idx);
+        }
+        self.targets[idx].1.extend_from_slice(line.as_bytes());
+    }
+
+    fn flush(&mut self) {
+        for idx in 0..self.targets.len() {
+            if !self.targets[idx].1.is_empty() {
+                self.send(idx);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::net::TcpListener;
+
+    #[test]
+    fn test_check_node() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        assert!(check_node(&address, "tcp").is_ok());
+        drop(listener);
+        assert!(check_node(&address, "tcp").is_err());
+
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let address = socket.local_addr().unwrap().to_string();
+        assert!(check_node(&address, "udp").is_ok());
+        drop(socket);
+        assert!(check_node(&address, "udp").is_err());
+    }
+
+    #[test]
+    fn test_proxy_move_metrics_of_dead_node() {
+        let downstream: Vec<_> = (0..2)
+            .map(|_| {
+                let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+                socket.set_read_timeout(Some(Duration::from_millis(500))).unwrap();
+                socket
+            })
+            .collect();
+        let cfg = ProxyConfig {
+            nodes: downstream.iter().map(|s| s.local_addr().unwrap().to_string()).collect(),
+            validate: true,
+            ..ProxyConfig::default()
+        };
+        let nodes = Arc::new(Nodes::new(&cfg.nodes));
+        let ring = HashRing::with_names(&cfg.nodes, 64);
+        let mut proxy = Proxy::new(&cfg, ring.clone(), nodes.clone());
+
+        let metric = "app.api.latency";
+        let (home, other) = (ring.position(metric), 1 - ring.position(metric));
+        let recv = |idx: usize| {
+            let mut buf = [0; 1024];
+            let size = downstream[idx].recv(&mut buf).unwrap();
+            String::from_utf8_lossy(&buf[..size]).into_owned()
+        };
+
+        proxy.push(Line::gauge(metric, 1.0));
+        proxy.flush();
+        assert_eq!(recv(home), "app.api.latency:1|g\n");
+
+        nodes.alive[home].store(false, Ordering::SeqCst);
+        proxy.push(Line::gauge(metric, 2.0));
+        proxy.flush();
+        assert_eq!(recv(other), "app.api.latency:2|g\n");
+
+        // the lines buffered before the node went down are dropped
+        nodes.alive[home].store(true, Ordering::SeqCst);
+        proxy.push(Line::gauge(metric, 3.0));
+        nodes.alive[home].store(false, Ordering::SeqCst);
+        proxy.flush();
+        assert!(proxy.targets[home].1.is_empty());
+    }
+}
@@ -0,0 +1,200 @@
+use std::io::{Error, Write, Result};
+use std::net::{SocketAddr, TcpStream, UdpSocket};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use worker::Line;
+use backend::{resolve, split_datagrams, MAX_UDP_PAYLOAD};
+use com;
+use config::SharedConfig;
+
+const TCP_TIMEOUT_MS: u64 = 500;
+/// the first wait before reconnecting a failed tcp target, doubled after
+/// each failed attempt up to the max
+const RECONNECT_MIN_MS: u64 = 1000;
+const RECONNECT_MAX_MS: u64 = 30_000;
+
+/// Repeater forwards every valid line received by a worker to the
+/// downstream statsd servers without aggregation. Each worker owns its
+/// own repeater, lines are buffered and sent once per received packet.
+pub struct Repeater {
+    targets: Vec<Target>,
+    buf: Vec<u8>,
+    shared: Arc<SharedConfig>,
+    /// the config generation which the targets are built from
+    generation: usize,
+}
+
+/// a downstream statsd which the lines are sent to
+pub enum Target {
+    Udp(UdpSocket, SocketAddr),
+    Tcp(TcpTarget),
+}
+
+/// a tcp downstream which is reconnected lazily after a failure. The lines
+/// are dropped until the backoff expires, so a dead downstream doesn't
+/// stall the sender with a connect timeout on every send.
+pub struct TcpTarget {
+    address: String,
+    conn: Option<TcpStream>,
+    /// no reconnect before it
+    retry_at: Option<Instant>,
+    backoff: Duration,
+}
+
+impl Repeater {
+    pub fn new(shared: Arc<SharedConfig>) -> Repeater {
+        let generation = shared.generation();
+        let config = shared.get();
+        let cfg = &config.repeater;
+        if !cfg.validate {
+            return Repeater {
+                targets: Vec::new(),
+                buf: Vec::new(),
+                shared,
+                generation,
+            };
+        }
+        let targets = cfg.targets
+            .iter()
+            .filter_map(|address| {
+                Target::new(address, &cfg.protocol)
+                    .map_err(|err| error!("repeater: skip target {}, error: {}", address, err))
+                    .ok()
+            })
+            .collect();
+        Repeater {
+            targets,
+            buf: Vec::with_capacity(MAX_UDP_PAYLOAD),
+            shared,
+            generation,
+        }
+    }
+
+    pub fn forward(&mut self, line: &Line) {
+        if self.targets.is_empty() {
+            return;
+        }
+        let line = format!("{}\n", line);
+        if self.buf.len() + line.len() > MAX_UDP_PAYLOAD {
+            self.flush();
+        }
+        self.buf.extend_from_slice(line.as_bytes());
+    }
+
+    /// send the buffered lines, and rebuild the targets if the config
+    /// is reloaded.
+    pub fn flush(&mut self) {
+        if !self.buf.is_empty() {
+            for target in &mut self.targets {
+                if let Err(err) = target.send(&self.buf) {
+                    warn!("repeater: fail to forward lines, error: {}", err);
+                }
+            }
+            self.buf.clear();
+        }
+        if self.generation != self.shared.generation() {
+            *self = Repeater::new(self.shared.clone());
+        }
+    }
+}
+
+impl Target {
+    /// protocol is "tcp" or "udp"
+    pub fn new(address: &str, protocol: &str) -> Result<Target> {
+        match protocol {
+            "tcp" => {
+                Ok(Target::Tcp(TcpTarget {
+                    address: address.to_owned(),
+                    conn: None,
+                    retry_at: None,
+                    backoff: Duration::from_millis(RECONNECT_MIN_MS),
+                }))
+            }
+            _ => {
+                let addr = resolve(address)?;
+                let socket = UdpSocket::bind("0.0.0.0:0")?;
+                Ok(Target::Udp(socket, addr))
+            }
+        }
+    }
+
+    pub fn send(&mut self, buf: &[u8]) -> Result<()> {
+        match *self {
+            Target::Udp(ref socket, ref addr) => {
+                for datagram in split_datagrams(buf, MAX_UDP_PAYLOAD) {
+                    socket.send_to(datagram, addr)?;
+                }
+                Ok(())
+            }
+            Target::Tcp(ref mut target) => target.send(buf),
+        }
+    }
+}
+
+impl TcpTarget {
+    fn send(&mut self, buf: &[u8]) -> Result<()> {
+        if self.conn.is_none() {
+            if self.retry_at.is_some_and(|retry_at| Instant::now() < retry_at) {
+                debug!("drop {} bytes to {} until reconnect", buf.len(), self.address);
+                return Ok(());
+            }
+            match self.connect() {
+                Ok(stream) => {
+                    self.conn = Some(stream);
+                    self.retry_at = None;
+                    self.backoff = Duration::from_millis(RECONNECT_MIN_MS);
+                }
+                Err(err) => {
+                    let backoff = self.backoff;
+                    self.retry_at = Some(Instant::now() + backoff);
+                    self.backoff = com::min(backoff * 2, Duration::from_millis(RECONNECT_MAX_MS));
+                    return Err(Error::new(err.kind(), format!("{}, retry in {:?}", err, backoff)));
+                }
+            }
+        }
+        let ret = self.conn.as_mut().map_or(Ok(()), |stream| stream.write_all(buf));
+        if ret.is_err() {
+            self.conn = None;
+        }
+        ret
+    }
+
+    fn connect(&self) -> Result<TcpStream> {
+        let timeout = Duration::from_millis(TCP_TIMEOUT_MS);
+        let stream = TcpStream::connect_timeout(&resolve(&self.address)?, timeout)?;
+        stream.set_write_timeout(Some(timeout))?;
+        Ok(stream)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::net::TcpListener;
+
+    #[test]
+    fn test_tcp_target_backoff() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        drop(listener);
+        let mut target = Target::new(&address, "tcp").unwrap();
+        assert!(target.send(b"a:1|c\n").is_err());
+        // dropped without connecting until the backoff expires
+        let start = Instant::now();
+        for _ in 0..100 {
+            assert!(target.send(b"a:1|c\n").is_ok());
+        }
+        assert!(start.elapsed() < Duration::from_millis(100));
+
+        let listener = TcpListener::bind(&address[..]).unwrap();
+        if let Target::Tcp(ref mut tcp) = target {
+            tcp.retry_at = Some(Instant::now());
+        }
+        assert!(target.send(b"a:1|c\n").is_ok());
+        assert!(listener.accept().is_ok());
+        if let Target::Tcp(ref tcp) = target {
+            assert_eq!(tcp.backoff, Duration::from_millis(RECONNECT_MIN_MS));
+        }
+    }
+}
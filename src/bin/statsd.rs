@@ -1,5 +1,26 @@
 extern crate libstatsd;
+extern crate serde_json;
+
+use std::process;
+
+use libstatsd::Config;
 
 fn main() {
+    let args = libstatsd::args();
+    if args.help {
+        println!("{}", libstatsd::usage());
+        return;
+    }
+    if args.version {
+        println!("statsd {}", env!("CARGO_PKG_VERSION"));
+        return;
+    }
+    if args.print_default_config {
+        println!("{}", serde_json::to_string_pretty(&Config::default()).unwrap());
+        return;
+    }
+    if args.check_config {
+        process::exit(if libstatsd::check_config() { 0 } else { 1 });
+    }
     libstatsd::run();
 }
@@ -0,0 +1,138 @@
+use getopts::Options;
+
+use config::Config;
+
+const DEFAULT_CONFIG: &str = "/srv/statsd-rs/etc/statsd.json";
+
+/// The command line arguments, the flag values override the config file.
+#[derive(Debug, Default, PartialEq)]
+pub struct Args {
+    pub config: String,
+    pub bind: Option<String>,
+    pub interval: Option<u64>,
+    /// env_logger filter, eg: "info" or "libstatsd=debug"
+    pub log_level: Option<String>,
+    pub check_config: bool,
+    pub version: bool,
+    pub print_default_config: bool,
+    pub help: bool,
+}
+
+fn options() -> Options {
+    let mut opts = Options::new();
+    opts.optopt("c", "config", "the config file as .json, .toml or .yaml", "PATH");
+    opts.optopt("b", "bind", "the address to receive metrics, override the config", "ADDR");
+    opts.optopt("i", "interval", "seconds between flushes, override the config", "SECS");
+    opts.optopt("l", "log-level", "the log filter, override RUST_LOG", "LEVEL");
+    opts.optflag("", "check-config", "validate the config file and exit");
+    opts.optflag("", "print-default-config", "print the default config as json and exit");
+    opts.optflag("V", "version", "print the version and exit");
+    opts.optflag("h", "help", "print this help and exit");
+    opts
+}
+
+pub fn usage() -> String {
+    let brief = format!("useage: statsd [options] [{}]", DEFAULT_CONFIG);
+    options().usage(&brief)
+}
+
+impl Args {
+    /// parse the arguments without the program name, the config file may
+    /// also be given as the only free argument.
+    pub fn parse<I: IntoIterator<Item = String>>(args: I) -> Result<Args, String> {
+        let matches = options().parse(args).map_err(|err| err.to_string())?;
+        let interval = match matches.opt_str("interval") {
+            Some(secs) => {
+                Some(secs.parse::<u64>()
+                    .map_err(|_| format!("interval {:?} is not a number of seconds", secs))?)
+            }
+            None => None,
+        };
+        if matches.free.len() > 1 {
+            return Err(format!("unexpected arguments {:?}", &matches.free[1..]));
+        }
+        let config = matches.opt_str("config")
+            .or_else(|| matches.free.first().cloned())
+            .unwrap_or(DEFAULT_CONFIG.to_owned());
+        Ok(Args {
+            config,
+            bind: matches.opt_str("bind"),
+            interval,
+            log_level: matches.opt_str("log-level"),
+            check_config: matches.opt_present("check-config"),
+            version: matches.opt_present("version"),
+            print_default_config: matches.opt_present("print-default-config"),
+            help: matches.opt_present("help"),
+        })
+    }
+
+    /// override the config by the flags.
+    pub fn apply(&self, config: &mut Config) {
+        if let Some(ref bind) = self.bind {
+            config.bind = bind.clone();
+        }
+        if let Some(interval) = self.interval {
+            config.interval = interval;
+        }
+    }
+}
+
+#[cfg(test)]
+fn get_args() -> Args {
+    Args { config: "etc/statsd.json".to_owned(), ..Args::default() }
+}
+
+#[cfg(not(test))]
+fn get_args() -> Args {
+    use std::env;
+    use std::process;
+    Args::parse(env::args().skip(1)).unwrap_or_else(|err| {
+        println!("{}\n{}", err, usage());
+        process::exit(2);
+    })
+}
+
+lazy_static! {
+    static ref ARGS: Args = get_args();
+}
+
+/// the arguments of this process.
+pub fn args() -> &'static Args {
+    &ARGS
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Args, String> {
+        Args::parse(args.iter().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn test_parse_args() {
+        let args = parse(&["--config", "a.toml", "--bind", "127.0.0.1:9125", "-i", "5",
+                           "--log-level", "debug", "--check-config"])
+            .unwrap();
+        assert_eq!(args.config, "a.toml");
+        assert_eq!(args.bind, Some("127.0.0.1:9125".to_owned()));
+        assert_eq!(args.interval, Some(5));
+        assert_eq!(args.log_level, Some("debug".to_owned()));
+        assert!(args.check_config);
+        assert!(!args.version);
+
+        assert_eq!(parse(&["b.json"]).unwrap().config, "b.json");
+        assert_eq!(parse(&[]).unwrap().config, DEFAULT_CONFIG);
+        assert!(parse(&["--interval", "ten"]).is_err());
+        assert!(parse(&["a.json", "b.json"]).is_err());
+    }
+
+    #[test]
+    fn test_flags_override_config() {
+        let args = parse(&["--bind", "127.0.0.1:9125", "--interval", "1"]).unwrap();
+        let mut config = Config::default();
+        args.apply(&mut config);
+        assert_eq!(config.bind, "127.0.0.1:9125");
+        assert_eq!(config.interval, 1);
+    }
+}
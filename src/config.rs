@@ -0,0 +1,903 @@
+use std::env;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::result;
+use std::sync::{Arc, RwLock};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use num_cpus;
+use serde_json::{self, Map, Value};
+use serde_yaml;
+use toml;
+
+use cli::args;
+use worker::{percentile, COMPRESSION_RANGE, ACCURACY_RANGE};
+
+/// every field has a default, a backend without its section is disabled
+/// and an unknown field, eg: a misspelled one, fails the load.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// the percentiles of the timer stats, eg: 99.9 for "upper_99_9", a
+    /// negative one for the lowest values
+    pub thresholds: Vec<f64>,
+    /// how the percentiles are read, "nearest_rank" as the stock statsd or
+    /// "linear" interpolation between the closest ranks
+    pub percentile_method: String,
+    /// weight the timer samples by their sample rates in the percentiles,
+    /// so a sample of `@0.1` takes the ranks of 10
+    pub weighted_percentiles: bool,
+    pub graphite: GraphiteConfig,
+    pub banshee: BansheeConfig,
+    pub influxdb: InfluxdbConfig,
+    pub opentsdb: OpentsdbConfig,
+    pub repeater: RepeaterConfig,
+    pub forward: ForwardConfig,
+    pub console: ConsoleConfig,
+    /// run as a proxy of the downstream statsd nodes instead of aggregating
+    pub proxy: ProxyConfig,
+    /// cap the timer samples kept in an interval by reservoir sampling
+    pub reservoir: ReservoirConfig,
+    /// one of "exact", "tdigest" or "ddsketch", the sketch modes keep the
+    /// timers in constant memory with approximate percentiles
+    pub timer_mode: String,
+    /// the centroids of a t-digest, more for less error
+    pub tdigest_compression: f64,
+    /// the relative error of the values read from a ddsketch
+    pub ddsketch_accuracy: f64,
+    pub interval: u64,
+    pub ring: usize,
+    pub dup: usize,
+    pub bind: String,
+    pub worker: usize,
+    /// the datagrams received by a worker in one syscall
+    pub recv_batch: usize,
+    /// SO_RCVBUF of each socket in bytes, 0 to keep the system default
+    pub rcvbuf: usize,
+    /// max seconds a backend has for the last flush when shutting down, a
+    /// regular flush has the interval
+    pub shutdown_timeout: u64,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct GraphiteConfig {
+    pub address: String,
+    /// one of "plaintext" or "pickle"
+    pub protocol: String,
+    #[serde(default = "enabled")]
+    pub validate: bool,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BansheeConfig {
+    pub address: String,
+    pub allow: Vec<String>,
+    #[serde(default = "enabled")]
+    pub validate: bool,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct InfluxdbConfig {
+    pub address: String,
+    /// one of "tcp", "udp" or "http"
+    pub protocol: String,
+    /// the write endpoint when send by http, eg: "/write"
+    pub path: String,
+    pub database: String,
+    /// timestamp precision, one of "n", "u", "ms", "s", "m" or "h"
+    pub precision: String,
+    #[serde(default = "enabled")]
+    pub validate: bool,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct OpentsdbConfig {
+    pub address: String,
+    /// tags attached to every data point, eg: ["host=web01"]
+    pub tags: Vec<String>,
+    #[serde(default = "enabled")]
+    pub validate: bool,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RepeaterConfig {
+    /// downstream statsd servers, eg: ["10.0.0.1:8125"]
+    pub targets: Vec<String>,
+    /// one of "udp" or "tcp"
+    pub protocol: String,
+    #[serde(default = "enabled")]
+    pub validate: bool,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ForwardConfig {
+    /// the upstream statsd
+    pub address: String,
+    /// one of "udp" or "tcp"
+    pub protocol: String,
+    /// "raw" to send every timer sample, "stats" to send the timer stats as gauges,
+    /// "merge" to send the samples or sketches for the upstream to merge
+    pub timer: String,
+    #[serde(default = "enabled")]
+    pub validate: bool,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ConsoleConfig {
+    /// one of "json" or "text"
+    pub format: String,
+    /// any of "counter", "gauge" and "timer", print all types when empty
+    pub types: Vec<String>,
+    #[serde(default = "enabled")]
+    pub validate: bool,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ProxyConfig {
+    /// the downstream statsd nodes, eg: ["10.0.0.1:8125"], a metric always
+    /// goes to the same alive node
+    pub nodes: Vec<String>,
+    /// one of "udp" or "tcp" to forward the lines
+    pub protocol: String,
+    /// one of "udp" or "tcp", the udp check can only find a closed port
+    pub check: String,
+    /// seconds between the health checks
+    pub check_interval: u64,
+    #[serde(default = "enabled")]
+    pub validate: bool,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ReservoirConfig {
+    /// max samples of a timer in an interval, 0 to keep all of them
+    pub size: usize,
+    /// the caps of the timers matching a pattern, the first match wins
+    pub patterns: Vec<PatternSize>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PatternSize {
+    /// a metric name where `*` matches any characters, eg: "app.api.*"
+    pub pattern: String,
+    pub size: usize,
+}
+
+impl ReservoirConfig {
+    /// the max samples of the timer metric, 0 for no cap.
+    pub fn cap(&self, metric: &str) -> usize {
+        self.patterns
+            .iter()
+            .find(|item| glob(&item.pattern, metric))
+            .map(|item| item.size)
+            .unwrap_or(self.size)
+    }
+}
+
+fn glob(pattern: &str, name: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or("");
+    if !name.starts_with(first) {
+        return false;
+    }
+    let mut rest = &name[first.len()..];
+    let mut parts: Vec<&str> = parts.collect();
+    let last = match parts.pop() {
+        Some(last) => last,
+        // no `*` at all
+        None => return rest.is_empty(),
+    };
+    for part in parts {
+        match rest.find(part) {
+            Some(pos) => rest = &rest[pos + part.len()..],
+            None => return false,
+        }
+    }
+    rest.ends_with(last)
+}
+
+/// a configured backend section is enabled unless it says `validate: false`
+fn enabled() -> bool {
+    true
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            thresholds: vec![90.0],
+            percentile_method: "nearest_rank".to_owned(),
+            weighted_percentiles: false,
+            graphite: GraphiteConfig::default(),
+            banshee: BansheeConfig::default(),
+            influxdb: InfluxdbConfig::default(),
+            opentsdb: OpentsdbConfig::default(),
+            repeater: RepeaterConfig::default(),
+            forward: ForwardConfig::default(),
+            console: ConsoleConfig::default(),
+            proxy: ProxyConfig::default(),
+            reservoir: ReservoirConfig::default(),
+            timer_mode: "exact".to_owned(),
+            tdigest_compression: 100.0,
+            ddsketch_accuracy: 0.01,
+            interval: 10,
+            ring: num_cpus::get(),
+            dup: 256,
+            bind: "0.0.0.0:8125".to_owned(),
+            worker: num_cpus::get(),
+            recv_batch: 16,
+            rcvbuf: 0,
+            shutdown_timeout: 3,
+        }
+    }
+}
+
+// the backend defaults are used when the section is missing, so they
+// are all disabled.
+
+impl Default for GraphiteConfig {
+    fn default() -> Self {
+        GraphiteConfig {
+            address: "127.0.0.1:2003".to_owned(),
+            protocol: "plaintext".to_owned(),
+            validate: false,
+        }
+    }
+}
+
+impl Default for BansheeConfig {
+    fn default() -> Self {
+        BansheeConfig {
+            address: "127.0.0.1:2015".to_owned(),
+            allow: Vec::new(),
+            validate: false,
+        }
+    }
+}
+
+impl Default for InfluxdbConfig {
+    fn default() -> Self {
+        InfluxdbConfig {
+            address: "127.0.0.1:8086".to_owned(),
+            protocol: "http".to_owned(),
+            path: "/write".to_owned(),
+            database: "statsd".to_owned(),
+            precision: "s".to_owned(),
+            validate: false,
+        }
+    }
+}
+
+impl Default for OpentsdbConfig {
+    fn default() -> Self {
+        OpentsdbConfig {
+            address: "127.0.0.1:4242".to_owned(),
+            tags: vec!["host=statsd".to_owned()],
+            validate: false,
+        }
+    }
+}
+
+impl Default for RepeaterConfig {
+    fn default() -> Self {
+        RepeaterConfig {
+            targets: Vec::new(),
+            protocol: "udp".to_owned(),
+            validate: false,
+        }
+    }
+}
+
+impl Default for ForwardConfig {
+    fn default() -> Self {
+        ForwardConfig {
+            address: "127.0.0.1:8125".to_owned(),
+            protocol: "udp".to_owned(),
+            timer: "raw".to_owned(),
+            validate: false,
+        }
+    }
+}
+
+impl Default for ConsoleConfig {
+    fn default() -> Self {
+        ConsoleConfig {
+            format: "text".to_owned(),
+            types: Vec::new(),
+            validate: false,
+        }
+    }
+}
+
+impl Default for ProxyConfig {
+    fn default() -> Self {
+        ProxyConfig {
+            nodes: Vec::new(),
+            protocol: "udp".to_owned(),
+            check: "udp".to_owned(),
+            check_interval: 10,
+            validate: false,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    /// can not read the config file
+    Io(io::Error),
+    /// the content is not a regular config
+    Parse(String),
+    /// Invalid(field path, reason)
+    Invalid(String, String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ConfigError::Io(ref err) => write!(f, "can not read the config file: {}", err),
+            ConfigError::Parse(ref reason) => write!(f, "config file is not valid: {}", reason),
+            ConfigError::Invalid(ref field, ref reason) => write!(f, "{}: {}", field, reason),
+        }
+    }
+}
+
+impl From<io::Error> for ConfigError {
+    fn from(oe: io::Error) -> ConfigError {
+        ConfigError::Io(oe)
+    }
+}
+
+/// the timer stats keys which not depend on thresholds
+const TIMER_KEYS: &[&str] = &["count", "count_ps", "upper", "lower", "sum", "mean", "median"];
+
+impl Config {
+    /// read, parse and validate the config file.
+    pub fn load<P: AsRef<Path>>(p: P) -> result::Result<Config, Vec<ConfigError>> {
+        let config = Config::from_file(p, env::vars()).map_err(|err| vec![err])?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// read the config file as json, toml or yaml by its extension, then
+    /// apply the `STATSD_` overrides of the env vars.
+    fn from_file<P, I>(p: P, vars: I) -> result::Result<Config, ConfigError>
+        where P: AsRef<Path>,
+              I: IntoIterator<Item = (String, String)>
+    {
+        let mut fp = File::open(p.as_ref())?;
+        let mut content = String::new();
+        fp.read_to_string(&mut content)?;
+        let ext = p.as_ref().extension().and_then(|ext| ext.to_str()).unwrap_or("json");
+        let mut value = parse(ext, &content)?;
+        let mut vars: Vec<_> = vars.into_iter().collect();
+        // the parent section goes before its fields
+        vars.sort();
+        override_env(&mut value, vars)?;
+        serde_json::from_value(value).map_err(|x| ConfigError::Parse(x.to_string()))
+    }
+
+    /// check all the fields and report every problem found.
+    pub fn validate(&self) -> result::Result<(), Vec<ConfigError>> {
+        let mut errors = Vec::new();
+        {
+            let mut invalid = |field: &str, reason: String| {
+                errors.push(ConfigError::Invalid(field.to_owned(), reason))
+            };
+
+            for (idx, &threshold) in self.thresholds.iter().enumerate() {
+                if threshold == 0.0 || threshold.is_nan() || threshold.abs() > 100.0 {
+                    invalid(&format!("thresholds[{}]", idx),
+                            format!("{} is out of range, must be in [-100, 100] and not 0",
+                                    threshold));
+                }
+            }
+            if self.interval == 0 {
+                invalid("interval", "must be greater than 0".to_owned());
+            }
+            if self.ring == 0 {
+                invalid("ring", "must be greater than 0".to_owned());
+            }
+            if self.dup == 0 || self.dup > 256 {
+                invalid("dup", format!("{} is out of range, must be in [1, 256]", self.dup));
+            }
+            if self.worker == 0 {
+                invalid("worker", "must be greater than 0".to_owned());
+            }
+            if self.recv_batch == 0 || self.recv_batch > 1024 {
+                invalid("recv_batch",
+                        format!("{} is out of range, must be in [1, 1024]", self.recv_batch));
+            }
+            if self.bind.parse::<SocketAddr>().is_err() {
+                invalid("bind", format!("{:?} is not a socket address", self.bind));
+            }
+
+            if self.graphite.validate {
+                check_address(&mut invalid, "graphite.address", &self.graphite.address);
+                check_one_of(&mut invalid,
+                             "graphite.protocol",
+                             &self.graphite.protocol,
+                             &["plaintext", "pickle"]);
+            }
+
+            if self.banshee.validate {
+                check_address(&mut invalid, "banshee.address", &self.banshee.address);
+                for (idx, key) in self.banshee.allow.iter().enumerate() {
+                    if !self.is_timer_key(key) {
+                        invalid(&format!("banshee.allow[{}]", idx),
+                                format!("{:?} is not a timer stats key", key));
+                    }
+                }
+            }
+
+            if self.influxdb.validate {
+                check_address(&mut invalid, "influxdb.address", &self.influxdb.address);
+                check_one_of(&mut invalid,
+                             "influxdb.protocol",
+                             &self.influxdb.protocol,
+                             &["tcp", "udp", "http"]);
+                check_one_of(&mut invalid,
+                             "influxdb.precision",
+                             &self.influxdb.precision,
+                             &["n", "ns", "u", "us", "ms", "s", "m", "h"]);
+                if self.influxdb.protocol == "http" && !self.influxdb.path.starts_with('/') {
+                    invalid("influxdb.path",
+                            format!("{:?} must start with '/'", self.influxdb.path));
+                }
+            }
+
+            if self.opentsdb.validate {
+                check_address(&mut invalid, "opentsdb.address", &self.opentsdb.address);
+                if self.opentsdb.tags.is_empty() {
+                    invalid("opentsdb.tags", "at least one tag is required".to_owned());
+                }
+                for (idx, tag) in self.opentsdb.tags.iter().enumerate() {
+                    let mut kv = tag.splitn(2, '=');
+                    let (key, value) = (kv.next().unwrap_or(""), kv.next().unwrap_or(""));
+                    if key.is_empty() || value.is_empty() || tag.contains(' ') {
+                        invalid(&format!("opentsdb.tags[{}]", idx),
+                                format!("{:?} is not a tagk=tagv pair", tag));
+                    }
+                }
+            }
+
+            if self.repeater.validate {
+                if self.repeater.targets.is_empty() {
+                    invalid("repeater.targets", "at least one target is required".to_owned());
+                }
+                for (idx, target) in self.repeater.targets.iter().enumerate() {
+                    check_address(&mut invalid, &format!("repeater.targets[{}]", idx), target);
+                }
+                check_one_of(&mut invalid,
+                             "repeater.protocol",
+                             &self.repeater.protocol,
+                             &["udp", "tcp"]);
+            }
+
+            if self.forward.validate {
+                check_address(&mut invalid, "forward.address", &self.forward.address);
+                check_one_of(&mut invalid,
+                             "forward.protocol",
+                             &self.forward.protocol,
+                             &["udp", "tcp"]);
+                check_one_of(&mut invalid,
+                             "forward.timer",
+                             &self.forward.timer,
+                             &["raw", "stats", "merge"]);
+            }
+
+            if self.console.validate {
+                check_one_of(&mut invalid,
+                             "console.format",
+                             &self.console.format,
+                             &["json", "text"]);
+                for (idx, kind) in self.console.types.iter().enumerate() {
+                    check_one_of(&mut invalid,
+                                 &format!("console.types[{}]", idx),
+                                 kind,
+                                 &["counter", "gauge", "timer"]);
+                }
+            }
+
+            if self.proxy.validate {
+                if self.proxy.nodes.is_empty() {
+                    invalid("proxy.nodes", "at least one node is required".to_owned());
+                }
+                for (idx, node) in self.proxy.nodes.iter().enumerate() {
+                    check_address(&mut invalid, &format!("proxy.nodes[{}]", idx), node);
+                }
+                check_one_of(&mut invalid, "proxy.protocol", &self.proxy.protocol, &["udp", "tcp"]);
+                check_one_of(&mut invalid, "proxy.check", &self.proxy.check, &["udp", "tcp"]);
+                if self.proxy.check_interval == 0 {
+                    invalid("proxy.check_interval", "must be greater than 0".to_owned());
+                }
+            }
+            check_one_of(&mut invalid,
+                         "percentile_method",
+                         &self.percentile_method,
+                         &["nearest_rank", "linear"]);
+            check_one_of(&mut invalid,
+                         "timer_mode",
+                         &self.timer_mode,
+                         &["exact", "tdigest", "ddsketch"]);
+            check_range(&mut invalid,
+                        "tdigest_compression",
+                        self.tdigest_compression,
+                        COMPRESSION_RANGE);
+            check_range(&mut invalid,
+                        "ddsketch_accuracy",
+                        self.ddsketch_accuracy,
+                        ACCURACY_RANGE);
+            for (idx, item) in self.reservoir.patterns.iter().enumerate() {
+                if item.pattern.is_empty() {
+                    invalid(&format!("reservoir.patterns[{}].pattern", idx),
+                            "must not be empty".to_owned());
+                }
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+
+    /// whether key is one of the stats caculated for timers
+    fn is_timer_key(&self, key: &str) -> bool {
+        if TIMER_KEYS.contains(&key) {
+            return true;
+        }
+        self.thresholds.iter().any(|&threshold| {
+            let bound = if threshold > 0.0 { "upper" } else { "lower" };
+            let suffix = percentile::suffix(threshold);
+            ["count", "mean", "sum", bound]
+                .iter()
+                .any(|prefix| key == format!("{}_{}", prefix, suffix))
+        })
+    }
+
+    /// keep the fields which can't be changed without a restart as old,
+    /// log an error for each of the changed one.
+    fn keep_restart_fields(&mut self, old: &Config) {
+        macro_rules! keep {
+            ($($field:ident),*) => {$(
+                if self.$field != old.$field {
+                    error!("config {} changed from {:?} to {:?}, need a restart to take effect",
+                           stringify!($field),
+                           old.$field,
+                           self.$field);
+                    self.$field = old.$field.clone();
+                }
+            )*}
+        }
+        keep!(bind, ring, dup, worker, recv_batch, rcvbuf, interval, proxy);
+    }
+}
+
+fn parse(ext: &str, content: &str) -> result::Result<Value, ConfigError> {
+    let ret = match ext {
+        "toml" => toml::from_str(content).map_err(|x| x.to_string()),
+        "yaml" | "yml" => serde_yaml::from_str(content).map_err(|x| x.to_string()),
+        _ => serde_json::from_str(content).map_err(|x| x.to_string()),
+    };
+    ret.map_err(ConfigError::Parse)
+}
+
+const ENV_PREFIX: &str = "STATSD_";
+
+/// Override the fields by env vars as `STATSD_<FIELD>[__<SUBFIELD>]`, eg:
+/// `STATSD_INTERVAL=5` or `STATSD_GRAPHITE__ADDRESS=10.0.0.1:2003`. The
+/// value is read as json, or as a plain string if it is not json or the
+/// field is a string. A var which names no field is skipped with a warning,
+/// and a backend missing in the file stays disabled unless its `__VALIDATE`
+/// is set.
+fn override_env<I>(root: &mut Value, vars: I) -> result::Result<(), ConfigError>
+    where I: IntoIterator<Item = (String, String)>
+{
+    // tell the string fields from the default config when the file misses them
+    let defaults = serde_json::to_value(Config::default()).unwrap_or(Value::Null);
+    for (name, raw) in vars {
+        if !name.starts_with(ENV_PREFIX) {
+            continue;
+        }
+        let path: Vec<_> = name[ENV_PREFIX.len()..]
+            .split("__")
+            .map(|seg| seg.to_lowercase())
+            .collect();
+        if path.iter().any(|seg| seg.is_empty()) {
+            continue;
+        }
+        let invalid = || {
+            ConfigError::Invalid(name.clone(),
+                                 format!("{} is not a config section", path.join(".")))
+        };
+
+        // eg: the STATSD_HOST of a client library in the same env
+        if lookup(&defaults, &path).is_none() {
+            warn!("skip env {}, {} is not a config field", name, path.join("."));
+            continue;
+        }
+
+        let is_string = matches!(lookup(root, &path).or_else(|| lookup(&defaults, &path)),
+                                 Some(&Value::String(_)));
+        let (last, parents) = path.split_last().unwrap();
+        let mut node = &mut *root;
+        for (depth, seg) in parents.iter().enumerate() {
+            let obj = node.as_object_mut().ok_or_else(&invalid)?;
+            if !obj.contains_key(seg) {
+                let mut section = Map::new();
+                // a missing section is enabled by default once it exists
+                if lookup(&defaults, &path[..depth + 1])
+                    .and_then(|x| x.get("validate"))
+                    .is_some() {
+                    section.insert("validate".to_owned(), Value::Bool(false));
+                }
+                obj.insert(seg.clone(), Value::Object(section));
+            }
+            node = obj.get_mut(seg).unwrap();
+        }
+        let obj = node.as_object_mut().ok_or_else(&invalid)?;
+        let value = if is_string {
+            Value::String(raw)
+        } else {
+            serde_json::from_str(&raw).unwrap_or(Value::String(raw))
+        };
+        debug!("override config {} by env {}", path.join("."), name);
+        obj.insert(last.clone(), value);
+    }
+    Ok(())
+}
+
+fn lookup<'a>(value: &'a Value, path: &[String]) -> Option<&'a Value> {
+    path.iter().try_fold(value, |node, seg| node.get(seg))
+}
+
+fn check_address<F: FnMut(&str, String)>(invalid: &mut F, field: &str, address: &str) {
+    let valid = match address.rfind(':') {
+        Some(pos) => pos > 0 && address[pos + 1..].parse::<u16>().is_ok(),
+        None => false,
+    };
+    if !valid {
+        invalid(field, format!("{:?} is not a host:port address", address));
+    }
+}
+
+fn check_one_of<F: FnMut(&str, String)>(invalid: &mut F,
+                                        field: &str,
+                                        value: &str,
+                                        allow: &[&str]) {
+    if !allow.contains(&value) {
+        invalid(field, format!("{:?} must be one of {:?}", value, allow));
+    }
+}
+
+fn check_range<F: FnMut(&str, String)>(invalid: &mut F,
+                                       field: &str,
+                                       value: f64,
+                                       (low, high): (f64, f64)) {
+    if !(value >= low && value <= high) {
+        invalid(field, format!("{} is out of range, must be in [{}, {}]", value, low, high));
+    }
+}
+
+/// load the config file given by the command line, with the flags applied.
+pub fn load_from_args() -> result::Result<Config, Vec<ConfigError>> {
+    let args = args();
+    let mut config = Config::from_file(&args.config, env::vars()).map_err(|err| vec![err])?;
+    args.apply(&mut config);
+    config.validate()?;
+    Ok(config)
+}
+
+/// The reloadable config of one server, the workers and adapters should
+/// check the `generation` to pick up a reloaded one.
+pub struct SharedConfig {
+    current: RwLock<Arc<Config>>,
+    /// bumped every time the config is reloaded.
+    generation: AtomicUsize,
+}
+
+impl SharedConfig {
+    pub fn new(config: Config) -> SharedConfig {
+        SharedConfig {
+            current: RwLock::new(Arc::new(config)),
+            generation: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn get(&self) -> Arc<Config> {
+        self.current.read().unwrap().clone()
+    }
+
+    pub fn generation(&self) -> usize {
+        self.generation.load(Ordering::SeqCst)
+    }
+
+    /// replace the current config, except the fields need a restart.
+    pub fn reload(&self, mut config: Config) {
+        let mut current = self.current.write().unwrap();
+        config.keep_restart_fields(&current);
+        info!("reload Config as: {:?}", config);
+        *current = Arc::new(config);
+        self.generation.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+/// validate the config file only, print every problem found.
+pub fn check_config() -> bool {
+    let pth = &args().config;
+    match load_from_args() {
+        Ok(_) => {
+            println!("config file {} is ok", pth);
+            true
+        }
+        Err(errors) => {
+            for err in errors {
+                println!("{}", err);
+            }
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Write;
+
+    fn load() -> Config {
+        Config::from_file("etc/statsd.json", Vec::new()).unwrap()
+    }
+
+    #[test]
+    fn test_reload_keep_restart_fields() {
+        let old = load();
+        let shared = SharedConfig::new(load());
+        let mut config = load();
+        config.bind = "0.0.0.0:1".to_owned();
+        config.thresholds = vec![99.0];
+        shared.reload(config);
+        assert_eq!(shared.generation(), 1);
+        assert_eq!(shared.get().bind, old.bind);
+        assert_eq!(shared.get().thresholds, vec![99.0]);
+    }
+
+    #[test]
+    fn test_validate_report_every_field() {
+        assert!(load().validate().is_ok());
+
+        let mut config = load();
+        config.thresholds = vec![90.0, 0.0, 101.0];
+        config.interval = 0;
+        config.bind = "localhost".to_owned();
+        config.banshee.allow = vec!["mean_90".to_owned(), "mean_99".to_owned()];
+        let fields: Vec<_> = config.validate()
+            .unwrap_err()
+            .into_iter()
+            .map(|err| match err {
+                ConfigError::Invalid(field, _) => field,
+                other => panic!("unexpected error {}", other),
+            })
+            .collect();
+        assert_eq!(fields,
+                   vec!["thresholds[1]", "thresholds[2]", "interval", "bind", "banshee.allow[1]"]);
+    }
+
+    #[test]
+    fn test_load_toml_and_yaml() {
+        let json = load();
+        assert_eq!(Config::from_file("etc/statsd.toml", Vec::new()).unwrap(), json);
+        assert_eq!(Config::from_file("etc/statsd.yaml", Vec::new()).unwrap(), json);
+    }
+
+    fn vars(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs.iter().map(|&(k, v)| (k.to_owned(), v.to_owned())).collect()
+    }
+
+    fn override_toml(content: &str, pairs: &[(&str, &str)]) -> result::Result<Config, ConfigError> {
+        let mut value = parse("toml", content).unwrap();
+        override_env(&mut value, vars(pairs))?;
+        serde_json::from_value(value).map_err(|x| ConfigError::Parse(x.to_string()))
+    }
+
+    #[test]
+    fn test_override_env() {
+        let config = override_toml("interval = 5\n[graphite]\naddress = \"a:1\"\n",
+                                   &[("STATSD_INTERVAL", "20"),
+                                     ("STATSD_GRAPHITE__ADDRESS", "10.0.0.1:2003"),
+                                     ("STATSD_INFLUXDB__DATABASE", "8086"),
+                                     ("STATSD_OPENTSDB__VALIDATE", "true"),
+                                     ("STATSD_OPENTSDB__ADDRESS", "10.0.0.1:4242"),
+                                     ("STATSD_THRESHOLDS", "[90, 99.9]"),
+                                     ("HOME", "/root")])
+            .unwrap();
+        assert_eq!(config.interval, 20);
+        assert_eq!(config.thresholds, vec![90.0, 99.9]);
+        assert!(config.graphite.validate);
+        assert_eq!(config.graphite.address, "10.0.0.1:2003");
+        // a field alone does not turn a backend on
+        assert!(!config.influxdb.validate);
+        assert_eq!(config.influxdb.database, "8086");
+        assert!(config.opentsdb.validate);
+        assert_eq!(config.opentsdb.address, "10.0.0.1:4242");
+    }
+
+    #[test]
+    fn test_override_env_skip_unknown_field() {
+        let config = override_toml("",
+                                   &[("STATSD_GRAPHITE__ADRESS", "1"),
+                                     ("STATSD_HOST", "10.0.0.1"),
+                                     ("STATSD_PORT", "8125"),
+                                     ("STATSD_INTERVAL__X", "1")])
+            .unwrap();
+        assert_eq!(config, Config::default());
+        // a known field still fails on a wrong value
+        match override_toml("", &[("STATSD_INTERVAL", "soon")]) {
+            Err(ConfigError::Parse(_)) => {}
+            other => panic!("the wrong interval is accepted: {:?}", other),
+        }
+        let config = Config::from_file("etc/statsd.json",
+                                       vars(&[("STATSD_INTERVAL", "7"), ("PATH", "/bin")]))
+            .unwrap();
+        assert_eq!(config.interval, 7);
+    }
+
+    #[test]
+    fn test_default_and_missing_section() {
+        let config: Config =
+            serde_json::from_str(r#"{"graphite": {"address": "10.0.0.1:2003"}}"#).unwrap();
+        assert_eq!(config.interval, 10);
+        assert_eq!(config.thresholds, vec![90.0]);
+        assert_eq!(config.bind, "0.0.0.0:8125");
+        assert!(config.graphite.validate);
+        assert_eq!(config.graphite.address, "10.0.0.1:2003");
+        assert_eq!(config.graphite.protocol, "plaintext");
+        assert!(!config.banshee.validate);
+        assert!(!config.console.validate);
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn test_misspelled_field() {
+        for content in &["intreval = 5\n",
+                         "[graphite]\nadress = \"10.0.0.1:2003\"\n",
+                         "[reservoir]\npatterns = [{pattern = \"a.*\", sise = 1}]\n"] {
+            let value = parse("toml", content).unwrap();
+            assert!(serde_json::from_value::<Config>(value).is_err(), "{}", content);
+        }
+        let path = env::temp_dir().join("statsd-misspelled-field.toml");
+        File::create(&path).unwrap().write_all(b"intreval = 5\n").unwrap();
+        match Config::from_file(&path, Vec::new()) {
+            Err(ConfigError::Parse(reason)) => assert!(reason.contains("intreval"), "{}", reason),
+            other => panic!("the misspelled field is accepted: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_reservoir_cap_by_pattern() {
+        let reservoir: ReservoirConfig = serde_json::from_str(r#"{
+            "size": 1000,
+            "patterns": [{"pattern": "app.*.latency", "size": 100},
+                         {"pattern": "app.*", "size": 0}]
+        }"#)
+            .unwrap();
+        assert_eq!(reservoir.cap("app.api.latency"), 100);
+        assert_eq!(reservoir.cap("app.api.latency.p"), 0);
+        assert_eq!(reservoir.cap("db.query"), 1000);
+        assert!(glob("a.b", "a.b"));
+        assert!(!glob("a.b", "a.bc"));
+        assert!(glob("*.b.*", "a.b.c"));
+        assert!(!glob("a*a", "a"));
+    }
+}
@@ -10,140 +10,105 @@ extern crate serde_derive;
 extern crate futures;
 extern crate env_logger;
 extern crate fnv;
+extern crate getopts;
+extern crate libc;
+extern crate mio;
 extern crate net2;
 extern crate num_cpus;
 extern crate serde;
 extern crate serde_json;
+extern crate serde_yaml;
 extern crate tokio_core;
+extern crate tokio_io;
+extern crate toml;
 extern crate test;
 
 mod worker;
 mod backend;
 mod ring;
+mod signal;
+mod config;
+mod cli;
+mod server;
 
-use std::path::Path;
-use std::sync::Arc;
+use std::env;
+use std::process;
 use std::thread;
+use std::time::Duration;
 
-use worker::{Worker, Adapter, MergeBuffer};
-use ring::HashRing;
+use config::load_from_args;
 
-pub fn run() {
-    env_logger::init().unwrap();
-    let ring = HashRing::new(CONFIG.ring, CONFIG.dup);
-    let merge_bufs: Vec<_> = (0..ring.num())
-        .into_iter()
-        .map(|_| MergeBuffer::new())
-        .collect();
-    let bufs = Arc::new(merge_bufs);
-
-    let workers: Vec<_> = (0..CONFIG.worker)
-        .map(|_| {
-            let nring = ring.clone();
-            let nbufs = bufs.clone();
-            thread::spawn(move || {
-                Worker::run(nring, nbufs);
-            })
-        })
-        .collect();
-
-    let adapters: Vec<_> = (0..ring.num())
-        .into_iter()
-        .map(|idx| {
-            let nbufs = bufs.clone();
-            thread::spawn(move || {
-                Adapter::run(nbufs.get(idx).unwrap());
-            })
-        })
-        .collect();
-
-    for (worker, adapter) in workers.into_iter().zip(adapters.into_iter()) {
-        worker.join().unwrap();
-        adapter.join().unwrap();
-    }
-}
+pub use config::{Config, ConfigError, GraphiteConfig, BansheeConfig, InfluxdbConfig,
+                 OpentsdbConfig, RepeaterConfig, ForwardConfig, ConsoleConfig, ProxyConfig,
+                 ReservoirConfig, PatternSize};
+pub use config::check_config;
+pub use cli::{Args, args, usage};
+pub use server::{Server, Builder};
 
-#[derive(Debug, PartialEq, Serialize, Deserialize)]
-pub struct Config {
-    pub thresholds: Vec<i64>,
-    pub graphite: GraphiteConfig,
-    pub banshee: BansheeConfig,
-    pub interval: u64,
-    pub ring: usize,
-    pub dup: usize,
-    pub bind: String,
-    pub worker: usize,
-}
-
-#[derive(Debug, PartialEq, Serialize, Deserialize)]
-pub struct GraphiteConfig {
-    pub address: String,
-    pub validate: bool,
-}
+/// how often the threads check the signals and shutdown state
+pub const SIGNAL_POLL_MS: u64 = 100;
 
-#[derive(Debug, PartialEq, Serialize, Deserialize)]
-pub struct BansheeConfig {
-    pub address: String,
-    pub allow: Vec<String>,
-    pub validate: bool,
-}
-
-fn usage() -> &'static str {
-    "\nuseage: statsd /srv/statsd-rs/etc/statsd.json"
-}
-
-impl Config {
-    fn load<P: AsRef<Path>>(p: P) -> Config {
-        use std::fs::File;
-        use std::io::Read;
-
-        let mut fp = File::open(p.as_ref())
-            .map_err(|x| {
-                println!("can not open the config file: {:?}, error: {}",
-                         p.as_ref(),
-                         x);
-                x
-            })
-            .expect(usage());
-        let mut content = String::new();
-        let _content_len = fp.read_to_string(&mut content).unwrap();
-        serde_json::from_str(&content)
-            .map_err(|x| {
-                error!("can not load content from config file, error: {}", x);
-                x
-            })
-            .expect("config file is not a regular json file")
+/// init the logger by `--log-level`, or `RUST_LOG` if not given.
+fn init_log() {
+    let mut builder = env_logger::LogBuilder::new();
+    if let Some(filter) = args().log_level.clone().or_else(|| env::var("RUST_LOG").ok()) {
+        builder.parse(&filter);
     }
+    builder.init().unwrap();
 }
 
-#[cfg(test)]
-fn get_cfg_path() -> String {
-    return "etc/statsd.json".to_owned();
-}
-
-#[cfg(not(test))]
-fn get_cfg_path() -> String {
-    use std::env;
-    let mut args = env::args().into_iter().skip(1);
-    let pth = args.next().unwrap_or("/srv/statsd-rs/etc/statsd.json".to_owned());
-    pth
+fn exit_with(errors: Vec<ConfigError>) -> ! {
+    for err in errors {
+        println!("{}", err);
+    }
+    println!("invalid config file {}\n{}", args().config, usage());
+    process::exit(1);
 }
 
-lazy_static! {
-    pub static ref CONFIG: Config  = {
-        let pth = get_cfg_path();
-        let config = Config::load(&pth);
-        info!("load Config as: {:?} from {}", config, pth);
-        config
-    };
+/// run a server by the command line, until SIGTERM or SIGINT, and reload
+/// the config file on SIGHUP.
+pub fn run() {
+    init_log();
+    signal::install(&[signal::SIGTERM, signal::SIGINT, signal::SIGHUP]);
+    let config = load_from_args().unwrap_or_else(|errors| exit_with(errors));
+    info!("load Config from {}", args().config);
+    let server = Server::builder(config).spawn().unwrap_or_else(|errors| exit_with(errors));
+
+    while !(signal::take(signal::SIGTERM) || signal::take(signal::SIGINT)) {
+        if signal::take(signal::SIGHUP) {
+            info!("receive SIGHUP, reload the config");
+            if let Err(errors) = load_from_args().and_then(|config| server.reload(config)) {
+                for err in errors {
+                    error!("fail to reload config: {}", err);
+                }
+                error!("keep the current config");
+            }
+        }
+        thread::sleep(Duration::from_millis(SIGNAL_POLL_MS));
+    }
+    info!("receive signal, stop receiving and flush all the merge buffers");
+    server.stop();
+    info!("statsd exit gracefully");
 }
 
 pub mod com {
     use std::time;
     pub fn now() -> u64 {
+        since_epoch().as_secs()
+    }
+
+    pub fn since_epoch() -> time::Duration {
         let now = time::SystemTime::now();
-        let nowd = now.duration_since(time::UNIX_EPOCH).unwrap();
-        nowd.as_secs()
+        now.duration_since(time::UNIX_EPOCH).unwrap()
+    }
+
+    pub fn millis(dur: time::Duration) -> f64 {
+        dur.as_secs() as f64 * 1000.0 + dur.subsec_nanos() as f64 / 1_000_000.0
+    }
+
+    /// the first multiple of interval after ts
+    pub fn next_boundary(ts: u64, interval: u64) -> u64 {
+        (ts / interval + 1) * interval
     }
 
     pub fn max<T: PartialOrd + Copy>(rhs: T, lhs: T) -> T {
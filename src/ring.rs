@@ -1,25 +1,40 @@
 use std::hash::Hasher;
 use std::default::Default;
+use std::sync::Arc;
 
 use fnv::FnvHasher;
-use num_cpus;
 
-use com;
-
-// Generate for a ring buffer into sub threads
+/// A consistent hash ring of the merge buffer slots, each slot is placed
+/// on the ring as `dup` virtual nodes. A metric goes to the first virtual
+/// node after its hash, so resizing the ring only moves about 1/num of
+/// the metrics.
 #[derive(Clone)]
 pub struct HashRing {
     num: usize,
-    dup: usize,
+    /// the sorted (hash, slot) of the virtual nodes
+    nodes: Arc<Vec<(u64, usize)>>,
 }
 
 impl HashRing {
     pub fn new(num: usize, dup: usize) -> HashRing {
-        let allow_num = com::min(num_cpus::get(), num);
-        let allow_dup = com::min(dup, 256);
+        let names: Vec<_> = (0..num).map(|slot| slot.to_string()).collect();
+        HashRing::with_names(&names, dup)
+    }
+
+    /// a ring of the named slots, eg: the addresses of the proxy nodes. The
+    /// virtual nodes are hashed from the names, so a slot keeps its metrics
+    /// when the others are reordered, added or removed.
+    pub fn with_names<S: AsRef<str>>(names: &[S], dup: usize) -> HashRing {
+        let mut nodes = Vec::with_capacity(names.len() * dup);
+        for (slot, name) in names.iter().enumerate() {
+            for replica in 0..dup {
+                nodes.push((hash(format!("{}-{}", name.as_ref(), replica).as_bytes()), slot));
+            }
+        }
+        nodes.sort();
         HashRing {
-            num: allow_num,
-            dup: allow_dup,
+            num: names.len(),
+            nodes: Arc::new(nodes),
         }
     }
 
@@ -28,8 +43,105 @@ impl HashRing {
     }
 
     pub fn position(&self, metric: &str) -> usize {
-        let mut hasher = FnvHasher::default();
-        hasher.write(metric.as_bytes());
-        (hasher.finish() as usize) % self.dup % self.num
+        let key = hash(metric.as_bytes());
+        let idx = self.first_node(key);
+        // wrap around to the first node
+        self.nodes.get(idx).unwrap_or(&self.nodes[0]).1
+    }
+
+    /// the position of the first slot which is alive after the metric, so
+    /// only the metrics of a dead slot are moved, to the next alive ones.
+    pub fn position_alive<F: Fn(usize) -> bool>(&self, metric: &str, alive: F) -> Option<usize> {
+        let idx = self.first_node(hash(metric.as_bytes()));
+        // walk the ring from the metric on, then wrap around to the start
+        let (before, after) = self.nodes.split_at(idx);
+        after.iter()
+            .chain(before.iter())
+            .map(|&(_, slot)| slot)
+            .find(|&slot| alive(slot))
+    }
+
+    fn first_node(&self, key: u64) -> usize {
+        match self.nodes.binary_search_by(|&(node, _)| node.cmp(&key)) {
+            Ok(idx) | Err(idx) => idx,
+        }
+    }
+}
+
+/// fnv with the murmur3 finalizer, fnv alone spreads the similar short
+/// keys poorly on the ring.
+fn hash(bytes: &[u8]) -> u64 {
+    let mut hasher = FnvHasher::default();
+    hasher.write(bytes);
+    let mut h = hasher.finish();
+    h ^= h >> 33;
+    h = h.wrapping_mul(0xff51afd7ed558ccd);
+    h ^= h >> 33;
+    h = h.wrapping_mul(0xc4ceb9fe1a85ec53);
+    h ^= h >> 33;
+    h
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_ring_distribution_skew() {
+        let ring = HashRing::new(24, 256);
+        let keys = 240_000;
+        let mut slots = vec![0; ring.num()];
+        for num in 0..keys {
+            slots[ring.position(&format!("app.api.latency.{}", num))] += 1;
+        }
+        let mean = (keys / ring.num()) as f64;
+        for &count in &slots {
+            let skew = (count as f64 - mean).abs() / mean;
+            assert!(skew < 0.2, "slots {:?} are skewed", slots);
+        }
+    }
+
+    #[test]
+    fn test_ring_rebalance_moves_few_keys() {
+        let (old, new) = (HashRing::new(24, 256), HashRing::new(25, 256));
+        let keys = 100_000;
+        let moved = (0..keys)
+            .map(|num| format!("app.api.latency.{}", num))
+            .filter(|key| old.position(key) != new.position(key))
+            .count();
+        // only the keys taken by the new slot move, about 1/25
+        assert!((moved as f64) < keys as f64 * 0.06, "{} keys moved", moved);
+    }
+
+    #[test]
+    fn test_ring_remove_named_slot_moves_its_keys() {
+        let names: Vec<_> = (0..5).map(|num| format!("10.0.0.{}:8125", num)).collect();
+        let mut rest = names.clone();
+        rest.remove(2);
+        rest.reverse();
+        let (old, new) = (HashRing::with_names(&names, 256), HashRing::with_names(&rest, 256));
+        for num in 0..10_000 {
+            let key = format!("app.api.latency.{}", num);
+            let (before, after) = (&names[old.position(&key)], &rest[new.position(&key)]);
+            if before != &names[2] {
+                assert_eq!(before, after);
+            }
+        }
+    }
+
+    #[test]
+    fn test_ring_skip_dead_slot() {
+        let ring = HashRing::new(4, 64);
+        for num in 0..1000 {
+            let key = format!("app.api.latency.{}", num);
+            let pos = ring.position(&key);
+            let alive = ring.position_alive(&key, |slot| slot != 2).unwrap();
+            if pos == 2 {
+                assert!(alive != 2);
+            } else {
+                assert_eq!(alive, pos);
+            }
+        }
+        assert_eq!(ring.position_alive("a", |_| false), None);
     }
 }
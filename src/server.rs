@@ -0,0 +1,338 @@
+use std::io;
+use std::mem;
+use std::net::{self, SocketAddr};
+use std::result;
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+
+use net2::{UdpBuilder, UdpSocketExt};
+use net2::unix::UnixUdpBuilderExt;
+
+use backend::proxy::{Proxy, Nodes};
+use config::{Config, ConfigError, SharedConfig};
+use ring::HashRing;
+use signal::Shutdown;
+use worker::{Worker, Adapter, Aggregate, MergeBuffer};
+
+/// Server is the handle of a running statsd instance, it owns its config,
+/// sockets and threads, so several instances can run in one process.
+/// Dropping the server stops it like `stop`.
+pub struct Server {
+    shared: Arc<SharedConfig>,
+    shutdown: Arc<Shutdown>,
+    local_addr: SocketAddr,
+    workers: Vec<JoinHandle<()>>,
+    adapters: Vec<JoinHandle<()>>,
+}
+
+pub struct Builder {
+    config: Config,
+}
+
+impl Server {
+    pub fn builder(config: Config) -> Builder {
+        Builder { config }
+    }
+
+    /// the current config, with the reloaded fields.
+    pub fn config(&self) -> Arc<Config> {
+        self.shared.get()
+    }
+
+    /// the address the workers receive at, useful when bind to port 0.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// validate and reload the config, the fields can't be changed without
+    /// a restart are kept as the current ones.
+    pub fn reload(&self, config: Config) -> result::Result<(), Vec<ConfigError>> {
+        config.validate()?;
+        self.shared.reload(config);
+        Ok(())
+    }
+
+    /// stop receiving, flush all the merge buffers for the last time and
+    /// wait for all the threads to exit.
+    pub fn stop(mut self) {
+        self.join();
+    }
+
+    fn join(&mut self) {
+        // stop all workers first, so nothing is pushed after the last flush
+        self.shutdown.stop();
+        join_all("worker", mem::take(&mut self.workers));
+        self.shutdown.drain();
+        join_all("adapter", mem::take(&mut self.adapters));
+    }
+}
+
+/// wait for all the threads, a panicked one is logged without panicking
+/// again, which would abort when the server is dropped while unwinding.
+fn join_all(kind: &str, threads: Vec<JoinHandle<()>>) {
+    for (idx, thread) in threads.into_iter().enumerate() {
+        if let Err(err) = thread.join() {
+            let reason = err.downcast_ref::<&str>()
+                .map(|reason| reason.to_string())
+                .or_else(|| err.downcast_ref::<String>().cloned())
+                .unwrap_or_else(|| "unknown".to_owned());
+            error!("{} {} panicked: {}", kind, idx, reason);
+        }
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        self.join();
+    }
+}
+
+impl Builder {
+    /// override the bind address of the config.
+    pub fn bind(mut self, bind: &str) -> Builder {
+        self.config.bind = bind.to_owned();
+        self
+    }
+
+    /// override the flush interval of the config.
+    pub fn interval(mut self, interval: u64) -> Builder {
+        self.config.interval = interval;
+        self
+    }
+
+    /// validate the config, bind the sockets and start the threads, as a
+    /// proxy if the proxy section is enabled.
+    pub fn spawn(self) -> result::Result<Server, Vec<ConfigError>> {
+        let config = self.config;
+        config.validate()?;
+        let sockets = bind_sockets(&config).map_err(|err| {
+                vec![ConfigError::Invalid("bind".to_owned(),
+                                          format!("can not bind {}: {}", config.bind, err))]
+            })?;
+        let local_addr = sockets[0].local_addr().unwrap();
+        info!("start a server at {} with Config: {:?}", local_addr, config);
+
+        let proxy = config.proxy.validate;
+        let shared = Arc::new(SharedConfig::new(config));
+        let shutdown = Arc::new(Shutdown::new());
+        let (workers, adapters) = if proxy {
+            spawn_proxy(sockets, &shutdown, &shared)
+        } else {
+            spawn_aggregate(sockets, &shutdown, &shared)
+        };
+
+        Ok(Server {
+            shared,
+            shutdown,
+            local_addr,
+            workers,
+            adapters,
+        })
+    }
+}
+
+type Threads = (Vec<JoinHandle<()>>, Vec<JoinHandle<()>>);
+
+/// the workers aggregate into the merge buffers of the ring slots, and
+/// each adapter flushes one of them to the backends.
+fn spawn_aggregate(sockets: Vec<net::UdpSocket>,
+                   shutdown: &Arc<Shutdown>,
+                   shared: &Arc<SharedConfig>)
+                   -> Threads {
+    let config = shared.get();
+    let ring = HashRing::new(config.ring, config.dup);
+    let merge_bufs: Vec<_> = (0..ring.num())
+        .map(|_| MergeBuffer::new(config.worker, &config))
+        .collect();
+    let bufs = Arc::new(merge_bufs);
+    let batch = config.recv_batch;
+
+    let workers = sockets.into_iter()
+        .enumerate()
+        .map(|(idx, socket)| {
+            let sink = Aggregate::new(idx, ring.clone(), bufs.clone(), shared.clone());
+            let nshutdown = shutdown.clone();
+            thread::spawn(move || {
+                Worker::run(socket, sink, batch, &nshutdown);
+            })
+        })
+        .collect();
+
+    let adapters = (0..ring.num())
+        .map(|idx| {
+            let nbufs = bufs.clone();
+            let nshared = shared.clone();
+            let nshutdown = shutdown.clone();
+            thread::spawn(move || {
+                Adapter::run(idx, nbufs.get(idx).unwrap(), nshared, &nshutdown);
+            })
+        })
+        .collect();
+    (workers, adapters)
+}
+
+/// the workers forward the lines to the downstream nodes, and the health
+/// checker takes the place of the adapters.
+fn spawn_proxy(sockets: Vec<net::UdpSocket>,
+               shutdown: &Arc<Shutdown>,
+               shared: &Arc<SharedConfig>)
+               -> Threads {
+    let config = shared.get();
+    let cfg = config.proxy.clone();
+    let ring = HashRing::with_names(&cfg.nodes, config.dup);
+    let nodes = Arc::new(Nodes::new(&cfg.nodes));
+    let batch = config.recv_batch;
+
+    let workers = sockets.into_iter()
+        .map(|socket| {
+            let sink = Proxy::new(&cfg, ring.clone(), nodes.clone());
+            let nshutdown = shutdown.clone();
+            thread::spawn(move || {
+                Worker::run(socket, sink, batch, &nshutdown);
+            })
+        })
+        .collect();
+
+    let nshutdown = shutdown.clone();
+    let checker = thread::spawn(move || {
+        nodes.check(&cfg, &nshutdown);
+    });
+    (workers, vec![checker])
+}
+
+/// bind one SO_REUSEPORT socket for each worker, all at the address of the
+/// first one so a port 0 bind works too. The address must be free, or the
+/// kernel would split the traffic with another server at it.
+fn bind_sockets(config: &Config) -> io::Result<Vec<net::UdpSocket>> {
+    let addr: SocketAddr = config.bind
+        .parse()
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "not a socket address"))?;
+    if addr.port() != 0 {
+        // a plain bind fails on any socket at the address, SO_REUSEPORT or not
+        drop(net::UdpSocket::bind(addr)?);
+    }
+    let first = bind_socket(&addr, config.rcvbuf)?;
+    let local_addr = first.local_addr()?;
+    let mut sockets = vec![first];
+    for _ in 1..config.worker {
+        sockets.push(bind_socket(&local_addr, config.rcvbuf)?);
+    }
+    Ok(sockets)
+}
+
+/// bind a socket with the receive buffer of `rcvbuf` bytes, the kernel caps
+/// it by net.core.rmem_max so a smaller one is only warned.
+fn bind_socket(addr: &SocketAddr, rcvbuf: usize) -> io::Result<net::UdpSocket> {
+    let builder = if addr.is_ipv4() {
+        UdpBuilder::new_v4()?
+    } else {
+        UdpBuilder::new_v6()?
+    };
+    let socket = builder.reuse_address(true)?
+        .reuse_port(true)?
+        .bind(addr)?;
+    if rcvbuf > 0 {
+        socket.set_recv_buffer_size(rcvbuf)?;
+        let size = socket.recv_buffer_size()?;
+        // linux doubles the value for the bookkeeping overhead
+        if size < rcvbuf {
+            warn!("rcvbuf is capped to {} bytes, raise net.core.rmem_max for {}",
+                  size,
+                  rcvbuf);
+        }
+    }
+    Ok(socket)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::Duration;
+
+    fn receiver() -> net::UdpSocket {
+        let socket = net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket.set_read_timeout(Some(Duration::from_millis(500))).unwrap();
+        socket
+    }
+
+    fn received(socket: &net::UdpSocket) -> Vec<String> {
+        let mut lines = Vec::new();
+        let mut buf = [0; 2048];
+        while let Ok(size) = socket.recv(&mut buf) {
+            let text = String::from_utf8_lossy(&buf[..size]).into_owned();
+            lines.extend(text.lines().map(|line| line.to_owned()));
+        }
+        lines
+    }
+
+    fn spawn(upstream: &net::UdpSocket) -> Server {
+        let mut config = Config {
+            bind: "127.0.0.1:0".to_owned(),
+            worker: 2,
+            ring: 1,
+            interval: 3600,
+            ..Config::default()
+        };
+        config.forward.address = upstream.local_addr().unwrap().to_string();
+        config.forward.validate = true;
+        Server::builder(config).spawn().unwrap()
+    }
+
+    #[test]
+    fn test_two_servers_in_one_process() {
+        let (up_a, up_b) = (receiver(), receiver());
+        let (server_a, server_b) = (spawn(&up_a), spawn(&up_b));
+        assert!(server_a.local_addr() != server_b.local_addr());
+
+        let client = net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        client.send_to(b"a:1|c", server_a.local_addr()).unwrap();
+        client.send_to(b"b:2|g", server_b.local_addr()).unwrap();
+        thread::sleep(Duration::from_millis(300));
+
+        // the last flush goes to the forward upstream of each server
+        server_a.stop();
+        server_b.stop();
+        let (lines_a, lines_b) = (received(&up_a), received(&up_b));
+        assert!(lines_a.contains(&"a:1|c".to_owned()));
+        assert!(!lines_a.contains(&"b:2|g".to_owned()));
+        assert!(lines_b.contains(&"b:2|g".to_owned()));
+        assert!(!lines_b.contains(&"a:1|c".to_owned()));
+    }
+
+    #[test]
+    fn test_join_panicked_thread() {
+        let threads = vec![thread::spawn(|| panic!("boom")), thread::spawn(|| {})];
+        join_all("worker", threads);
+    }
+
+    #[test]
+    fn test_spawn_report_bind_error() {
+        let taken = net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let config = Config::default();
+        let errors = Server::builder(config)
+            .bind(&taken.local_addr().unwrap().to_string())
+            .spawn()
+            .err()
+            .unwrap();
+        match errors[0] {
+            ConfigError::Invalid(ref field, _) => assert_eq!(field, "bind"),
+            ref other => panic!("unexpected error {}", other),
+        }
+    }
+
+    #[test]
+    fn test_second_server_same_address() {
+        let upstream = receiver();
+        let first = spawn(&upstream);
+        let errors = Server::builder(Config::default())
+            .bind(&first.local_addr().to_string())
+            .spawn()
+            .err()
+            .unwrap();
+        match errors[0] {
+            ConfigError::Invalid(ref field, _) => assert_eq!(field, "bind"),
+            ref other => panic!("unexpected error {}", other),
+        }
+        first.stop();
+    }
+}
@@ -0,0 +1,61 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use libc;
+
+pub use libc::{SIGHUP, SIGINT, SIGTERM};
+
+/// bit mask of the signals received but not taken yet
+static PENDING: AtomicUsize = AtomicUsize::new(0);
+
+extern "C" fn on_signal(sig: libc::c_int) {
+    PENDING.fetch_or(1 << sig, Ordering::SeqCst);
+}
+
+/// install the handler which records the signals for `take`.
+pub fn install(sigs: &[libc::c_int]) {
+    for &sig in sigs {
+        let handler = on_signal as extern "C" fn(libc::c_int) as libc::sighandler_t;
+        if unsafe { libc::signal(sig, handler) } == libc::SIG_ERR {
+            error!("fail to install handler for signal {}", sig);
+        }
+    }
+}
+
+/// check and clear whether sig is received since the last take.
+pub fn take(sig: libc::c_int) -> bool {
+    let bit = 1 << sig;
+    PENDING.fetch_and(!bit, Ordering::SeqCst) & bit != 0
+}
+
+const RUNNING: usize = 0;
+const STOPPING: usize = 1;
+const DRAINING: usize = 2;
+
+/// Shutdown is shared between the main thread, workers and adapters:
+/// workers stop receiving when stopping, and adapters flush the merge
+/// buffers for the last time when draining, after all workers exited.
+pub struct Shutdown {
+    state: AtomicUsize,
+}
+
+impl Shutdown {
+    pub fn new() -> Shutdown {
+        Shutdown { state: AtomicUsize::new(RUNNING) }
+    }
+
+    pub fn stop(&self) {
+        self.state.store(STOPPING, Ordering::SeqCst);
+    }
+
+    pub fn drain(&self) {
+        self.state.store(DRAINING, Ordering::SeqCst);
+    }
+
+    pub fn is_stopping(&self) -> bool {
+        self.state.load(Ordering::SeqCst) >= STOPPING
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.state.load(Ordering::SeqCst) == DRAINING
+    }
+}
@@ -0,0 +1,166 @@
+//! The merge export ships the timer state of an interval to an upstream
+//! statsd, which merges it into its own timers, so the percentiles are
+//! computed over the samples of all the nodes. Each timer is sent as one
+//! or more lines of `metric:payload|kind`:
+//!
+//! - `ts`: `count;seen;v1,v2,...`, the samples of a `TimeSet`, as
+//!   `v1*weight,...` when weighted
+//! - `td`: `count;len;sum;min;max;compression;0;mean*weight,...`, a t-digest
+//! - `dd`: `count;len;sum;min;max;accuracy;zero;idx*weight,nidx*weight,...`,
+//!   a ddsketch, the negative buckets prefixed by `n`
+//!
+//! A state too large for a line is split into several lines which add up
+//! to the whole one when merged, each of them a state of its own, so a
+//! lost line only takes its samples away. A line out of the bounds of a
+//! state, eg: a NaN, no samples, a count below the samples or a huge
+//! compression, is rejected or clamped.
+
+use std::fmt;
+
+use super::{Sketch, TimeSet};
+
+/// Export is the timer state parsed from a merge line.
+#[derive(Clone, Debug)]
+pub enum Export {
+    Samples(TimeSet),
+    Sketch(Sketch),
+}
+
+impl Export {
+    pub fn parse(kind: &str, payload: &str) -> Option<Export> {
+        match kind {
+            "ts" => decode_samples(payload).map(Export::Samples),
+            _ => Sketch::decode(kind, payload).map(Export::Sketch),
+        }
+    }
+}
+
+/// a merge line is re-encoded as a single one.
+impl fmt::Display for Export {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let lines = match *self {
+            Export::Samples(ref set) => encode_samples(set, usize::MAX),
+            Export::Sketch(ref sketch) => sketch.encode(usize::MAX),
+        };
+        write!(f, "{}", lines[0])
+    }
+}
+
+/// the `payload|ts` of the samples, the count and seen are split by the
+/// samples of each line.
+pub fn encode_samples(set: &TimeSet, max: usize) -> Vec<String> {
+    let &TimeSet(ref values, count, seen, ref weights) = set;
+    let items: Vec<_> = values.iter()
+        .enumerate()
+        .map(|(idx, value)| match weights.get(idx) {
+            Some(weight) => format!("{}*{}", value, weight),
+            None => value.to_string(),
+        })
+        .collect();
+    // leave room for the count and seen
+    let parts = chunks(&items, max.saturating_sub(64));
+    let len = values.len() as f64;
+    let (mut count_left, mut seen_left) = (count, seen);
+    parts.iter()
+        .enumerate()
+        .map(|(idx, part)| {
+            let (part_count, part_seen) = if idx + 1 == parts.len() {
+                (count_left, seen_left)
+            } else {
+                let share = part.len() as f64 / len;
+                (count * share, seen * part.len() as u64 / values.len() as u64)
+            };
+            count_left -= part_count;
+            seen_left -= part_seen;
+            format!("{};{};{}|ts", part_count, part_seen, part.join(","))
+        })
+        .collect()
+}
+
+fn decode_samples(payload: &str) -> Option<TimeSet> {
+    let mut fields = payload.splitn(3, ';');
+    let count: f64 = fields.next()?.parse().ok()?;
+    let seen = fields.next()?.parse().ok()?;
+    if !(count >= 0.0 && count.is_finite()) {
+        return None;
+    }
+    let (mut values, mut weights) = (Vec::new(), Vec::new());
+    for item in fields.next()?.split(',').filter(|item| !item.is_empty()) {
+        let mut parts = item.splitn(2, '*');
+        let value: f64 = parts.next()?.parse().ok()?;
+        if !value.is_finite() {
+            return None;
+        }
+        values.push(value);
+        if let Some(weight) = parts.next() {
+            // the same bound as the sample rates
+            let weight: f64 = weight.parse().ok()?;
+            if !(weight > 0.0 && weight.is_finite()) {
+                return None;
+            }
+            weights.push(weight);
+        }
+    }
+    // all the samples are weighted or none, each stands for one seen and
+    // at least one of the count, with the rounding of a split line
+    let len = values.len() as f64;
+    if values.is_empty() || !weights.is_empty() && weights.len() != values.len() ||
+       seen < values.len() as u64 || count < len - 1e-6 * len {
+        return None;
+    }
+    Some(TimeSet(values, count, seen, weights))
+}
+
+/// split the items into chunks which join into at most max bytes, with
+/// at least one item in each.
+pub fn chunks(items: &[String], max: usize) -> Vec<&[String]> {
+    let mut parts = Vec::new();
+    let (mut start, mut size) = (0, 0);
+    for (idx, item) in items.iter().enumerate() {
+        if idx > start && size + item.len() + 1 > max {
+            parts.push(&items[start..idx]);
+            start = idx;
+            size = 0;
+        }
+        size += item.len() + 1;
+    }
+    parts.push(&items[start..]);
+    parts
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use worker::{Line, Merge};
+
+    #[test]
+    fn test_split_samples_add_up() {
+        let values: Vec<_> = (0..1000).map(|num| num as f64 + 0.5).collect();
+        let set = TimeSet(values.clone(), 2000.0, 3000, Vec::new());
+        let lines = encode_samples(&set, 1432);
+        assert!(lines.len() > 1);
+        let (mut merged, mut count, mut seen) = (Vec::new(), 0.0, 0);
+        for line in &lines {
+            assert!(line.len() <= 1432);
+            let input = format!("api:{}", line);
+            let line = Line::parse(input.as_bytes()).unwrap();
+            assert_eq!(format!("{}", line), input);
+            match line.kind {
+                Merge(export) => match *export {
+                    Export::Samples(TimeSet(values, part_count, part_seen, _)) => {
+                        merged.extend(values);
+                        count += part_count;
+                        seen += part_seen;
+                    }
+                    other => panic!("unexpected {:?}", other),
+                },
+                other => panic!("unexpected {:?}", other),
+            }
+        }
+        assert_eq!((merged, count, seen), (values, 2000.0, 3000));
+        assert!(Line::parse(b"api:1;x|ts").is_err());
+        assert!(Line::parse(b"api:3;2;1*2,2|ts").is_err());
+        let weighted = Line::parse(b"api:3;2;1*2,2*1|ts").unwrap();
+        assert_eq!(format!("{}", weighted), "api:3;2;1*2,2*1|ts");
+    }
+}
@@ -1,139 +1,166 @@
 use std::collections::HashMap;
+use std::collections::hash_map::Entry;
 use std::convert::From;
-use std::io::{self, Error};
-use std::net::{SocketAddr, ToSocketAddrs};
+use std::fmt;
+use std::io::Error;
+use std::net;
 use std::num::ParseFloatError;
 use std::mem;
 use std::ops::DerefMut;
 use std::result;
-use std::string::FromUtf8Error;
+use std::str::{self, Utf8Error};
 use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
 
 use futures::stream::Stream;
-use futures::{Async, Poll};
-use tokio_core::net::{UdpCodec, UdpSocket};
-use tokio_core::reactor::{Handle, Core};
-use net2::UdpBuilder;
-use net2::unix::UnixUdpBuilderExt;
+use futures::Future;
+use tokio_core::reactor::{Core, Interval};
 
-use ::CONFIG;
+use ::SIGNAL_POLL_MS;
+use com;
 use backend::BackEndSender;
-use com::now;
+use backend::repeater::Repeater;
+use config::{Config, ReservoirConfig, SharedConfig};
 use ring::HashRing;
+use signal::Shutdown;
+
+mod export;
+pub mod percentile;
+mod recv;
+mod reservoir;
+mod sketch;
+
+pub use self::export::{Export, encode_samples};
+pub use self::sketch::{Sketch, TimerMode, COMPRESSION_RANGE, ACCURACY_RANGE};
+use self::percentile::{Method, Ranks, Sorted};
+use self::recv::Recv;
+use self::reservoir::{Reservoir, Rng};
 
 const CLCR: u8 = '\n' as u8;
+/// the max size of a udp datagram
+const MAX_DATAGRAM: usize = 65536;
+/// the max datagrams handled in one poll before yielding
+const RECV_BUDGET: usize = 64;
 
 pub struct Worker;
 
-impl Worker {
-    pub fn run(ring: HashRing, bufs: Arc<Vec<MergeBuffer>>) {
-        let mut core = Core::new().unwrap();
-        let handle = core.handle();
-        let socket = Self::build_socket(&*CONFIG.bind.clone(), &handle, true);
-        info!("worker: bind at {:?}", &CONFIG.bind);
-        let service = socket.framed(RecvCodec {}).flatten().for_each(|item| {
-            let pos = ring.position(&item.metric);
-            Ok(bufs[pos].push(item))
-        });
-        core.run(service).unwrap();
-    }
-
-    fn build_socket<T: ToSocketAddrs>(bind: T, handle: &Handle, reuse_port: bool) -> UdpSocket {
-        let socket = UdpBuilder::new_v4()
-            .expect("udp port is full")
-            .reuse_address(true)
-            .expect("SO_ADDRESS not support")
-            .reuse_port(reuse_port)
-            .expect("SO_REUSEPORT not support")
-            .bind(bind)
-            .map_err(|err| {
-                error!("bind faild error: {}", err);
-                err
-            })
-            .unwrap();
-        UdpSocket::from_socket(socket, handle).expect("can't convert from std::net::UdpSocket")
+/// Sink takes the lines received by a worker.
+pub trait Sink {
+    fn push(&mut self, line: Line);
+
+    /// the self metrics of the worker
+    fn report(&mut self, line: Line) {
+        self.push(line);
     }
-}
 
-pub struct RecvCodec;
+    /// called after every received batch of packets
+    fn flush(&mut self) {}
+}
 
-impl UdpCodec for RecvCodec {
-    type In = Packet;
-    type Out = ();
+/// Aggregate pushes the lines into the merge buffer of their ring slot,
+/// and repeats them when the repeater is enabled.
+pub struct Aggregate {
+    idx: usize,
+    ring: HashRing,
+    bufs: Arc<Vec<MergeBuffer>>,
+    repeater: Repeater,
+}
 
-    fn decode(&mut self, _addr: &SocketAddr, buf: &[u8]) -> io::Result<Self::In> {
-        debug!("get a new packet");
-        Ok(Packet::from(buf.to_vec()))
+impl Aggregate {
+    pub fn new(idx: usize,
+               ring: HashRing,
+               bufs: Arc<Vec<MergeBuffer>>,
+               shared: Arc<SharedConfig>)
+               -> Aggregate {
+        Aggregate {
+            idx,
+            ring,
+            bufs,
+            repeater: Repeater::new(shared),
+        }
     }
 
-    fn encode(&mut self, _out: Self::Out, _into: &mut Vec<u8>) -> SocketAddr {
-        unreachable!("never send back !");
+    /// push the line into the merge buffer of its ring slot.
+    fn emit_line(&mut self, line: Line) {
+        let pos = self.ring.position(line.metric);
+        self.bufs[pos].push(self.idx, line);
     }
 }
 
-pub struct Packet {
-    buf: Vec<u8>,
-    size: usize,
+impl Sink for Aggregate {
+    fn push(&mut self, line: Line) {
+        self.repeater.forward(&line);
+        self.emit_line(line);
+    }
+
+    /// the self metrics are not repeated
+    fn report(&mut self, line: Line) {
+        self.emit_line(line);
+    }
+
+    fn flush(&mut self) {
+        self.repeater.flush();
+    }
 }
 
-impl Packet {
-    pub fn from(buf: Vec<u8>) -> Packet {
-        let size = buf.len();
-        Packet {
-            buf: buf,
-            size: size,
+impl Worker {
+    /// receive from socket until shutdown is stopping, the socket is bound
+    /// by the server so the bind error is reported before any thread starts.
+    /// Up to `batch` datagrams are received in one syscall.
+    pub fn run<S: Sink>(socket: net::UdpSocket, sink: S, batch: usize, shutdown: &Shutdown) {
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+        info!("worker: receive at {}", socket.local_addr().unwrap());
+        let recv = Recv::new(socket, sink, batch, &handle)
+            .expect("can't register the socket to the reactor");
+        let stop = Interval::new(Duration::from_millis(SIGNAL_POLL_MS), &handle)
+            .unwrap()
+            .map_err(StatsdError::from)
+            .take_while(move |_| Ok(!shutdown.is_stopping()))
+            .for_each(|_| Ok(()));
+        match core.run(recv.select(stop)) {
+            Ok(_) => info!("worker: stopped"),
+            // only a fatal receive error gets here, eg: a closed socket
+            Err((err, _)) => error!("worker: stopped with error {:?}", err),
         }
     }
 }
 
+/// Packet iterates the valid lines of a datagram, the invalid ones are
+/// skipped.
+pub struct Packet<'a> {
+    buf: &'a [u8],
+}
 
-impl Stream for Packet {
-    type Item = Line;
-    type Error = StatsdError;
-
-    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
-        if self.buf.len() == 0 {
-            debug!("a full packet was parsed");
-            if self.size == 0 {
-                return Ok(Async::Ready(None));
-            } else {
-                let ret = Ok(Async::Ready(Some(Line::report("statsd.recv", self.size))));
-                self.size = 0;
-                return ret;
-            }
-        }
-
-        let mut drained: Vec<_> = match self.buf.iter().position(|x| x == &CLCR) {
-            Some(pos) => self.buf.drain(..pos + 1).into_iter().collect(),
-            None => self.buf.drain(..).into_iter().collect(),
-        };
+impl<'a> Packet<'a> {
+    pub fn new(buf: &'a [u8]) -> Packet<'a> {
+        Packet { buf }
+    }
+}
 
-        if drained.len() > 0 && drained[drained.len() - 1] == CLCR {
-            let _clcr = drained.pop().unwrap();
-        }
+impl<'a> Iterator for Packet<'a> {
+    type Item = Line<'a>;
 
-        let line_str = match String::from_utf8(drained) {
-            Ok(item) => {
-                debug!("get a new line {}", &item);
-                item
-            }
-            Err(err) => {
-                warn!("parse line error {:?}", err);
-                return Ok(Async::NotReady);
-            }
-        };
+    fn next(&mut self) -> Option<Line<'a>> {
+        while !self.buf.is_empty() {
+            let (line, rest) = match self.buf.iter().position(|x| x == &CLCR) {
+                Some(pos) => (&self.buf[..pos], &self.buf[pos + 1..]),
+                None => (self.buf, &self.buf[self.buf.len()..]),
+            };
+            self.buf = rest;
 
-        match Line::parse(line_str) {
-            Ok(line) => Ok(Async::Ready(Some(line))),
-            Err(err) => {
-                warn!("parse error : {:?}", err);
-                Ok(Async::NotReady)
+            match Line::parse(line) {
+                Ok(line) => return Some(line),
+                Err(err) => warn!("parse error : {:?}", err),
             }
         }
+        debug!("a full packet was parsed");
+        None
     }
 }
 
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Debug)]
 pub enum Kind {
     /// Gauge(Gauge)
     Gauge(f64),
@@ -141,13 +168,18 @@ pub enum Kind {
     Count(f64),
     /// Time(Value, count)
     Time(f64, f64),
+    /// Merge(the timer state exported by a downstream statsd)
+    Merge(Box<Export>),
 }
 
-pub use self::Kind::{Time, Count, Gauge};
+pub use self::Kind::{Time, Count, Gauge, Merge};
 
 impl Kind {
     fn parse(value_str: &str, kind_str: &str, rate_str: &str) -> Result<Kind> {
         let rate = rate_str.parse::<f64>()?;
+        if !(rate > 0.0 && rate <= 1.0) {
+            return Err(StatsdError::WrongRate(rate));
+        }
 
         match kind_str {
             "ms" => {
@@ -162,28 +194,40 @@ impl Kind {
                 let value = value_str.parse::<f64>().unwrap_or(0.0);
                 Ok(Gauge(value))
             }
+            "ts" | "td" | "dd" => {
+                let export = Export::parse(kind_str, value_str).ok_or(StatsdError::WrongLine)?;
+                Ok(Merge(Box::new(export)))
+            }
             _ => Err(StatsdError::UnknownKind(kind_str.to_owned())),
         }
     }
 }
 
+/// Line borrows the metric name from the received datagram, it is only
+/// copied when the metric is first inserted into the merge buffer.
 #[derive(Clone, Debug)]
-pub struct Line {
-    pub metric: String,
+pub struct Line<'a> {
+    pub metric: &'a str,
     pub kind: Kind,
 }
 
-impl Line {
-    fn report(metric: &str, value: usize) -> Line {
-        let metric = metric.to_owned();
-        let kind = Kind::Count(value as f64);
+impl<'a> Line<'a> {
+    fn report(metric: &'a str, value: usize) -> Line<'a> {
         Line {
-            metric: metric,
-            kind: kind,
+            metric,
+            kind: Kind::Count(value as f64),
         }
     }
 
-    fn parse(input: String) -> Result<Line> {
+    pub fn gauge(metric: &'a str, value: f64) -> Line<'a> {
+        Line {
+            metric,
+            kind: Kind::Gauge(value),
+        }
+    }
+
+    pub fn parse(input: &'a [u8]) -> Result<Line<'a>> {
+        let input = str::from_utf8(input)?;
         let mut lsp = input.split(":");
         let metric = lsp.next().ok_or(StatsdError::WrongLine)?;
         let bits = lsp.next().ok_or(StatsdError::WrongLine)?;
@@ -193,20 +237,36 @@ impl Line {
         // sample rate support
         let rate_str = bsp.next().map(|v| v.trim_matches('@')).unwrap_or("1.0");
         let kind = Kind::parse(value_str.trim(), kind_str.trim(), rate_str.trim())?;
-        Ok(Line {
-            metric: metric.to_owned(),
-            kind: kind,
-        })
+        Ok(Line { metric, kind })
+    }
+}
+
+/// Format the line back into statsd wire format, the sample rate of
+/// counters is already applied to the value.
+impl<'a> fmt::Display for Line<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.kind {
+            Gauge(value) => write!(f, "{}:{}|g", self.metric, value),
+            Count(value) => write!(f, "{}:{}|c", self.metric, value),
+            Time(value, 1.0) => write!(f, "{}:{}|ms", self.metric, value),
+            Time(value, count) => write!(f, "{}:{}|ms|@{}", self.metric, value, 1.0 / count),
+            Merge(ref export) => write!(f, "{}:{}", self.metric, export),
+        }
     }
 }
 
 #[derive(Clone, Debug)]
 pub struct ValueCount(pub f64, pub f64);
+/// the samples of a timer, the count weighted by the sample rates, the
+/// number of pushed samples, which is more than the samples kept once the
+/// reservoir is full, and the weights of the samples by their rates, empty
+/// unless the percentiles are weighted.
 #[derive(Clone, Debug)]
-pub struct TimeSet(pub Vec<f64>, pub f64);
+pub struct TimeSet(pub Vec<f64>, pub f64, pub u64, pub Vec<f64>);
 
 
 pub type TimeMap = HashMap<String, TimeSet>;
+pub type SketchMap = HashMap<String, Sketch>;
 pub type TimeData = HashMap<String, HashMap<String, f64>>;
 
 pub type CountMap = HashMap<String, ValueCount>;
@@ -220,47 +280,140 @@ pub struct LightBuffer {
     pub gauge: GaugeData,
     pub count: CountData,
     pub time: TimeData,
+    /// the sorted raw samples of timers
+    pub samples: TimeMap,
+    /// the timers of the sketch modes instead of the samples
+    pub sketches: SketchMap,
 }
 
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use std::thread;
     use test::Bencher;
 
     #[test]
     fn test_caculate_time() {
         let values: Vec<_> = (0..100_000).into_iter().map(|_| 1.0).collect();
         let count = values.len() as f64;
-        let ts = TimeSet(values, count);
+        let ts = TimeSet(values, count, count as u64, Vec::new());
         let mut tm = TimeMap::new();
         tm.insert("test.hello".to_owned(), ts);
-        let td = LightBuffer::caculate_time(tm);
+        let config = Config { interval: 5, ..Config::default() };
+        let td = LightBuffer::caculate_time(&mut tm, &config);
         let subs = td.get("test.hello").unwrap();
         assert_eq!(*subs.get("count_ps").unwrap(), count / 5.0);
     }
 
+    #[test]
+    fn test_caculate_time_percentiles() {
+        let ts = TimeSet(vec![4.0, 1.0, 3.0, 2.0], 4.0, 4, Vec::new());
+        let mut tm = TimeMap::new();
+        tm.insert("t".to_owned(), ts);
+        let mut config = Config { thresholds: vec![50.0, 99.9, -100.0], ..Config::default() };
+        let td = LightBuffer::caculate_time(&mut tm, &config);
+        let stats = &td["t"];
+        assert_eq!(stats["median"], 2.5);
+        assert_eq!((stats["count_50"], stats["upper_50"], stats["sum_50"]), (2.0, 2.0, 3.0));
+        assert_eq!((stats["count_99_9"], stats["upper_99_9"]), (4.0, 4.0));
+        assert_eq!((stats["count_100"], stats["lower_100"], stats["sum_100"]), (4.0, 1.0, 10.0));
+
+        config.percentile_method = "linear".to_owned();
+        let td = LightBuffer::caculate_time(&mut tm, &config);
+        assert_eq!(td["t"]["upper_50"], 2.5);
+        assert!((td["t"]["upper_99_9"] - 3.997).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_line_display_round_trip() {
+        for input in &["api.hits:3|c", "api.latency:12.5|ms|@0.5", "queue:7|g"] {
+            let line = Line::parse(input.as_bytes()).unwrap();
+            assert_eq!(&format!("{}", line), input);
+        }
+        let sampled = Line::parse(b"api.hits:3|c|@0.5").unwrap();
+        assert_eq!(format!("{}", sampled), "api.hits:6|c");
+    }
+
+    #[test]
+    fn test_reject_wrong_rate() {
+        for input in &["t:1|ms|@0", "t:1|ms|@-0.5", "a:1|c|@2", "a:1|c|@NaN"] {
+            match Line::parse(input.as_bytes()) {
+                Err(StatsdError::WrongRate(_)) => {}
+                other => panic!("{} parsed as {:?}", input, other),
+            }
+        }
+        assert!(Line::parse(b"t:1|ms|@1").is_ok());
+        assert!(Line::parse(b"a:1|c|@0.001").is_ok());
+    }
+
+    #[test]
+    fn test_packet_skip_invalid_line() {
+        let packet = Packet::new(b"a.b:1|c\nbad line\n\xff:1|c\nt:5|ms");
+        let lines: Vec<_> = packet.map(|line| line.metric).collect();
+        assert_eq!(lines, vec!["a.b", "t"]);
+    }
+
     #[bench]
     fn bench_caculate_time(b: &mut Bencher) {
         let values: Vec<_> = (0..10000).into_iter().map(|_| 1.0).collect();
         let count = values.len() as f64;
-        let ts = TimeSet(values, count);
+        let ts = TimeSet(values, count, count as u64, Vec::new());
         let mut tm = TimeMap::new();
         for number in 0..400 {
             tm.insert(format!("test.hello.{}", number), ts.clone());
         }
+        let config = Config::default();
         b.iter(|| {
-            let _td = LightBuffer::caculate_time(tm.clone());
+            let _td = LightBuffer::caculate_time(&mut tm.clone(), &config);
         });
     }
 
+    fn bench_packet() -> Vec<u8> {
+        (0..20)
+            .map(|num| format!("app.api.{0}.latency:{0}|ms|@0.5\napp.api.{0}.hits:1|c", num))
+            .collect::<Vec<_>>()
+            .join("\n")
+            .into_bytes()
+    }
+
+    #[bench]
+    fn bench_parse_packet(b: &mut Bencher) {
+        let buf = bench_packet();
+        b.iter(|| Packet::new(&buf).count())
+    }
+
+    /// the parsing before it was zero-copy: copy the datagram, drain a new
+    /// vec for each line and own the metric name.
+    #[bench]
+    fn bench_parse_packet_owned(b: &mut Bencher) {
+        let buf = bench_packet();
+        b.iter(|| {
+            let mut packet = buf.to_vec();
+            let mut lines = Vec::new();
+            while !packet.is_empty() {
+                let mut drained: Vec<_> = match packet.iter().position(|x| x == &CLCR) {
+                    Some(pos) => packet.drain(..pos + 1).collect(),
+                    None => mem::take(&mut packet),
+                };
+                if drained.last() == Some(&CLCR) {
+                    drained.pop();
+                }
+                let input = String::from_utf8(drained).unwrap();
+                let line = Line::parse(input.as_bytes()).unwrap();
+                lines.push((line.metric.to_owned(), line.kind));
+            }
+            lines.len()
+        })
+    }
+
     #[bench]
     fn bench_push_time(b: &mut Bencher) {
         b.iter(|| bench_push_time_single())
     }
 
     fn bench_push_time_single() {
-        let buf = MergeBuffer::new();
+        let buf = MergeBuffer::new(1, &Config::default());
         let keys: Vec<_> = (0..400)
             .into_iter()
             .map(|num| format!("test.hello.{}", num))
@@ -271,173 +424,538 @@ mod test {
                 let kind = Kind::Time(1.0, 1.0);
                 let line = Line {
                     kind: kind,
-                    metric: keys[num].clone(),
+                    metric: &keys[num],
                 };
-                buf.push(line);
+                buf.push(0, line);
+            }
+        }
+    }
+
+    /// 4 workers push into the same shard, as all workers shared one
+    /// lock before the buffer was sharded.
+    #[bench]
+    fn bench_push_time_contended_shared(b: &mut Bencher) {
+        b.iter(|| bench_push_time_threads(|_| 0))
+    }
+
+    #[bench]
+    fn bench_push_time_contended_sharded(b: &mut Bencher) {
+        b.iter(|| bench_push_time_threads(|worker| worker))
+    }
+
+    fn bench_push_time_threads<F: Fn(usize) -> usize>(shard: F) {
+        let workers = 4;
+        let buf = Arc::new(MergeBuffer::new(workers, &Config::default()));
+        let threads: Vec<_> = (0..workers)
+            .map(|worker| {
+                let buf = buf.clone();
+                let shard = shard(worker);
+                thread::spawn(move || {
+                    for _ in 0..100 {
+                        for num in 0..400 {
+                            let metric = format!("test.hello.{}", num);
+                            let line = Line {
+                                kind: Kind::Time(1.0, 1.0),
+                                metric: &metric,
+                            };
+                            buf.push(shard, line);
+                        }
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_merge_shards() {
+        let buf = MergeBuffer::new(2, &Config::default());
+        buf.push(0, Line::parse(b"a:1|c").unwrap());
+        buf.push(1, Line::parse(b"a:2|c").unwrap());
+        buf.push(0, Line::parse(b"t:1|ms").unwrap());
+        buf.push(1, Line::parse(b"t:3|ms|@0.5").unwrap());
+        buf.report(Line::gauge("g", 7.0));
+
+        let light = buf.truncate(10, &Config::default());
+        let ValueCount(value, count) = light.count["a"].clone();
+        assert_eq!((value, count), (3.0, 2.0));
+        assert_eq!(light.samples["t"].0, vec![1.0, 3.0]);
+        assert_eq!(light.samples["t"].1, 3.0);
+        assert_eq!(light.gauge["g"], 7.0);
+        assert!(buf.truncate(20, &Config::default()).count.is_empty());
+    }
+
+    #[test]
+    fn test_merge_keep_last_gauge() {
+        let buf = MergeBuffer::new(2, &Config::default());
+        buf.push(1, Line::gauge("g", 1.0));
+        buf.push(0, Line::gauge("g", 2.0));
+        buf.push(0, Line::gauge("h", 3.0));
+        buf.push(1, Line::gauge("h", 4.0));
+        let light = buf.truncate(10, &Config::default());
+        assert_eq!((light.gauge["g"], light.gauge["h"]), (2.0, 4.0));
+    }
+
+    #[test]
+    fn test_reservoir_cap_timer_samples() {
+        let mut config = Config::default();
+        config.reservoir.size = 10;
+        let buf = MergeBuffer::new(2, &config);
+        for num in 0..1000 {
+            buf.push(num % 2, Line::parse(b"t:2|ms").unwrap());
+        }
+        let light = buf.truncate(10, &config);
+        assert_eq!(light.samples["t"].0.len(), 10);
+        assert_eq!(light.time["t"]["count"], 1000.0);
+        assert_eq!(light.time["t"]["sum"], 2000.0);
+        assert_eq!(light.time["t"]["mean"], 2.0);
+    }
+
+    #[test]
+    fn test_weighted_percentiles() {
+        for mode in &["exact", "tdigest", "ddsketch"] {
+            for &weighted in &[false, true] {
+                let config = Config {
+                    timer_mode: mode.to_string(),
+                    weighted_percentiles: weighted,
+                    ..Config::default()
+                };
+                let buf = MergeBuffer::new(2, &config);
+                // 90 fast ones, and the 10 slow ones stand for 100
+                for num in 0..100 {
+                    let line: &[u8] = if num < 90 { b"t:1|ms" } else { b"t:100|ms|@0.1" };
+                    buf.push(num % 2, Line::parse(line).unwrap());
+                }
+                let light = buf.truncate(10, &config);
+                let stats = &light.time["t"];
+                assert_eq!(stats["count"], 190.0);
+                let (median, count_90) = if weighted { (100.0, 171.0) } else { (1.0, 90.0) };
+                assert!((stats["median"] - median).abs() / median < 0.02,
+                        "{} {} median {}",
+                        mode,
+                        weighted,
+                        stats["median"]);
+                assert_eq!(stats["count_90"], count_90, "{} {}", mode, weighted);
+            }
+        }
+    }
+
+    #[test]
+    fn test_merge_hostile_lines() {
+        let hostile: &[&[u8]] = &[b"evil:NaN;1;5;5;5;100;0;5*1|td",
+                                  b"evil:1;1;5;5;5;100;0;5*-1|td",
+                                  b"evil:1;3;5;5;5;100;0;5*1|td",
+                                  b"evil:1;1;5;5;5;1e-300;0;1*1|dd",
+                                  b"evil:-1;1;5|ts",
+                                  b"evil:1;0;5|ts",
+                                  b"evil:1;1;inf|ts",
+                                  b"evil:1;1;5*-1|ts",
+                                  b"evil:1;1;5*0|ts",
+                                  b"evil:1;1;5*inf|ts",
+                                  b"evil:1;0;0;0;0;100;0;|td",
+                                  b"evil:5;0;|ts",
+                                  b"evil:1;2;5,6|ts"];
+        for input in hostile {
+            assert!(Line::parse(input).is_err(), "{}", String::from_utf8_lossy(input));
+        }
+        for mode in &["exact", "tdigest", "ddsketch"] {
+            let config = Config { timer_mode: mode.to_string(), ..Config::default() };
+            let buf = MergeBuffer::new(1, &config);
+            // a huge compression is clamped instead of allocated
+            buf.push(0, Line::parse(b"evil:1;1;5;5;5;1e18;0;5*1|td").unwrap());
+            buf.push(0, Line::parse(b"evil:1;1;5|ts").unwrap());
+            buf.push(0, Line::parse(b"evil:5|ms").unwrap());
+            let light = buf.truncate(10, &config);
+            let stats = &light.time["evil"];
+            assert_eq!((stats["count"], stats["upper"], stats["lower"]), (3.0, 5.0, 5.0));
+            assert!(stats.values().all(|value| value.is_finite()), "{} {:?}", mode, stats);
+        }
+    }
+
+    #[test]
+    fn test_empty_sketch_only_count() {
+        for mode in &["tdigest", "ddsketch"] {
+            let config = Config { timer_mode: mode.to_string(), ..Config::default() };
+            let mut sketches = SketchMap::new();
+            sketches.insert("t".to_owned(), Sketch::new(TimerMode::new(&config)).unwrap());
+            let time = LightBuffer::caculate_sketch(&sketches, &config);
+            let mut keys: Vec<_> = time["t"].keys().cloned().collect();
+            keys.sort();
+            assert_eq!(keys, vec!["count", "count_ps"]);
+            assert_eq!(time["t"]["count"], 0.0);
+        }
+    }
+
+    #[test]
+    fn test_sketch_merge_capped_samples() {
+        let config = Config { timer_mode: "tdigest".to_owned(), ..Config::default() };
+        let buf = MergeBuffer::new(1, &config);
+        // 10 samples kept out of the 100 seen
+        buf.push(0, Line::parse(b"t:100;100;1,2,3,4,5,6,7,8,9,10|ts").unwrap());
+        let light = buf.truncate(10, &config);
+        let stats = &light.time["t"];
+        assert_eq!(light.sketches["t"].len, 100.0);
+        assert_eq!((stats["count"], stats["count_90"]), (100.0, 90.0));
+        assert_eq!(stats["sum"], 550.0);
+    }
+
+    #[test]
+    fn test_sketch_timer_mode() {
+        for mode in &["tdigest", "ddsketch"] {
+            let config = Config { timer_mode: mode.to_string(), ..Config::default() };
+            let buf = MergeBuffer::new(2, &config);
+            for num in 1..1001 {
+                let line = format!("t:{}|ms", num);
+                buf.push(num % 2, Line::parse(line.as_bytes()).unwrap());
+            }
+            let light = buf.truncate(10, &config);
+            assert!(light.samples.is_empty());
+            let stats = &light.time["t"];
+            assert_eq!((stats["count"], stats["sum"], stats["upper"]), (1000.0, 500500.0, 1000.0));
+            assert_eq!(stats["count_90"], 900.0);
+            for &(key, exact) in &[("upper_90", 900.0), ("median", 500.0), ("mean_90", 450.5)] {
+                let error = (stats[key] - exact).abs() / exact;
+                assert!(error < 0.02, "{} {} is {}", mode, key, stats[key]);
             }
         }
     }
 }
 
 impl LightBuffer {
-    fn caculate_time(time: TimeMap) -> TimeData {
+    /// caculate the timer stats, the samples are sorted in place.
+    fn caculate_time(time: &mut TimeMap, config: &Config) -> TimeData {
         debug!("caculate time value start");
         let mut time_data = TimeData::new();
-        for (key, TimeSet(mut values, sample_count)) in time {
-            let mut current = HashMap::new();
+        for (key, &mut TimeSet(ref mut values, sample_count, seen, ref mut weights)) in
+            time.iter_mut() {
             if values.len() == 0 {
+                let mut current = HashMap::new();
                 current.insert("count".to_string(), 0.0);
                 current.insert("count_ps".to_string(), 0.0);
-                time_data.insert(key, current);
+                time_data.insert(key.clone(), current);
                 continue;
             }
 
-            values.sort_by(|v1, v2| v1.partial_cmp(v2).unwrap());
-
-            let count = values.len();
-            let min = values[0];
-            let max = values[count - 1];
-            let mut sum = values[0];
-            let mut mean = min;
-            let mut boundary = max;
-            let mut cumulative: Vec<f64> = vec![min];
-            let mut latest = min;
-
-            for &val in values.iter().skip(1) {
-                let nval = val + latest;
-                cumulative.push(nval);
-                latest = nval;
-            }
-
-            for &threshold in &CONFIG.thresholds[..] {
-                let abs_threshold = threshold.abs();
-                let mut threshold_num = count;
-                if count > 1 {
-                    threshold_num = (threshold.abs() as f64 * ((count as f64) / 100.0)) as usize;
-                    if threshold_num == 0 {
-                        continue;
-                    }
-                    if threshold > 0 {
-                        boundary = values[threshold_num - 1];
-                        sum = cumulative[threshold_num - 1];
-                    } else {
-                        boundary = values[count - threshold_num];
-                        sum = cumulative[count - 1] - cumulative[count - threshold_num - 1];
-                    }
-                    mean = sum / threshold_num as f64;
+            if weights.is_empty() {
+                values.sort_by(|v1, v2| v1.partial_cmp(v2).unwrap());
+            } else {
+                let mut pairs: Vec<_> = values.drain(..).zip(weights.drain(..)).collect();
+                pairs.sort_by(|p1, p2| p1.0.partial_cmp(&p2.0).unwrap());
+                for (value, weight) in pairs {
+                    values.push(value);
+                    weights.push(weight);
                 }
-                current.insert(format!("count_{}", abs_threshold), threshold_num as f64);
-                current.insert(format!("mean_{}", abs_threshold), mean);
-                current.insert(format!("{}_{}",
-                                       if threshold > 0 { "upper" } else { "lower" },
-                                       abs_threshold),
-                               boundary);
-                current.insert(format!("sum_{}", abs_threshold), sum);
             }
-
-            sum = cumulative[count - 1];
-            mean = sum / count as f64;
-            let median = if count == 1 {
-                values[0]
-            } else if count % 2 == 0 {
-                values[count / 2]
+            let sorted = Sorted::new(values, weights);
+            // the sums and counts of a sample are scaled to all seen values
+            let scale = if weights.is_empty() {
+                com::max(seen as f64 / values.len() as f64, 1.0)
             } else {
-                (values[count / 2 - 1] + values[count / 2]) / 2.0
+                com::max(sample_count / sorted.len(), 1.0)
             };
+            let current = LightBuffer::timer_stats(&sorted, sample_count, scale, config);
+            time_data.insert(key.clone(), current);
+        }
+        time_data
+    }
 
-            // NOT SUPPORT stddev
-            current.insert("upper".to_owned(), max);
-            current.insert("lower".to_owned(), min);
-            current.insert("count".to_owned(), sample_count);
-            current.insert("count_ps".to_owned(), sample_count / CONFIG.interval as f64);
-
-            current.insert("sum".to_owned(), sum);
-            current.insert("mean".to_owned(), mean);
-            current.insert("median".to_owned(), median);
+    /// caculate the timer stats of the sketch modes the same way, the ranks
+    /// are read from the sketches.
+    fn caculate_sketch(sketches: &SketchMap, config: &Config) -> TimeData {
+        sketches.iter()
+            .map(|(key, sketch)| {
+                (key.clone(), LightBuffer::timer_stats(sketch, sketch.count, 1.0, config))
+            })
+            .collect()
+    }
 
-            time_data.insert(key, current);
+    /// the stats of the ranked values of a timer, the count is weighted by
+    /// the sample rates and scale is the seen values over the ranked ones.
+    fn timer_stats<R: Ranks>(ranks: &R,
+                             count: f64,
+                             scale: f64,
+                             config: &Config)
+                             -> HashMap<String, f64> {
+        let method = Method::new(config);
+        let mut current = HashMap::new();
+        current.insert("count".to_owned(), count);
+        current.insert("count_ps".to_owned(), count / config.interval as f64);
+        // no value to rank, eg: an empty sketch
+        if ranks.len() <= 0.0 {
+            return current;
         }
-        time_data
+        for &threshold in &config.thresholds[..] {
+            let stats = match percentile::threshold(ranks, threshold, method) {
+                Some(stats) => stats,
+                None => continue,
+            };
+            let suffix = percentile::suffix(threshold);
+            current.insert(format!("count_{}", suffix), stats.count * scale);
+            current.insert(format!("mean_{}", suffix), stats.mean);
+            current.insert(format!("{}_{}", if threshold > 0.0 { "upper" } else { "lower" }, suffix),
+                           stats.boundary);
+            current.insert(format!("sum_{}", suffix), stats.sum * scale);
+        }
+
+        // NOT SUPPORT stddev
+        current.insert("upper".to_owned(), ranks.max());
+        current.insert("lower".to_owned(), ranks.min());
+
+        current.insert("sum".to_owned(), ranks.sum() * scale);
+        current.insert("mean".to_owned(), ranks.sum() / ranks.len());
+        current.insert("median".to_owned(), percentile::median(ranks));
+        current
     }
 }
 
+/// MergeBuffer aggregates the metrics of one ring slot. Each worker owns
+/// a shard of it, so pushing only contends with the flush of the adapter,
+/// never with the other workers. The shards are merged at flush.
 pub struct MergeBuffer {
-    time: Arc<Mutex<TimeMap>>,
-    count: Arc<Mutex<CountMap>>,
-    gauge: Arc<Mutex<GaugeMap>>,
+    shards: Vec<Mutex<Shard>>,
+    seq: Arc<AtomicUsize>,
 }
 
-impl MergeBuffer {
-    pub fn new() -> MergeBuffer {
-        let buf = MergeBuffer {
-            time: Arc::new(Mutex::new(TimeMap::new())),
-            count: Arc::new(Mutex::new(CountMap::new())),
-            gauge: Arc::new(Mutex::new(GaugeMap::new())),
+/// how the shards keep the timers in an interval, a reloaded config takes
+/// effect in the next one.
+struct Timers {
+    mode: TimerMode,
+    reservoir: ReservoirConfig,
+    weighted: bool,
+}
+
+impl Timers {
+    fn new(config: &Config) -> Timers {
+        Timers {
+            mode: TimerMode::new(config),
+            reservoir: config.reservoir.clone(),
+            weighted: config.weighted_percentiles,
+        }
+    }
+
+    fn timer(&self, metric: &str) -> Timer {
+        match Sketch::new(self.mode) {
+            Some(sketch) => Timer::Sketch(sketch),
+            None => Timer::Exact(Reservoir::new(self.reservoir.cap(metric))),
+        }
+    }
+}
+
+enum Timer {
+    Exact(Reservoir),
+    Sketch(Sketch),
+}
+
+impl Timer {
+    /// a weighted sample takes the ranks of its count in the percentiles.
+    fn push(&mut self, value: f64, count: f64, weighted: bool, rng: &mut Rng) {
+        match *self {
+            Timer::Exact(ref mut reservoir) => reservoir.push(value, count, weighted, rng),
+            Timer::Sketch(ref mut sketch) => {
+                sketch.insert(value, count, if weighted { count } else { 1.0 })
+            }
+        }
+    }
+
+    /// merge other into self, the samples go into the sketch when the mode
+    /// differs.
+    fn merge(&mut self, other: Timer, rng: &mut Rng) {
+        let ours = mem::replace(self, Timer::Exact(Reservoir::new(0)));
+        *self = match (ours, other) {
+            (Timer::Exact(mut ours), Timer::Exact(theirs)) => {
+                ours.merge(theirs, rng);
+                Timer::Exact(ours)
+            }
+            (Timer::Sketch(mut ours), Timer::Sketch(theirs)) => {
+                ours.merge(theirs);
+                Timer::Sketch(ours)
+            }
+            (Timer::Exact(exact), Timer::Sketch(mut sketch)) |
+            (Timer::Sketch(mut sketch), Timer::Exact(exact)) => {
+                // a kept sample stands for seen / len ones, as the scale of
+                // the exact stats
+                let TimeSet(values, count, seen, weights) = exact.set;
+                let each = count / values.len() as f64;
+                let scale = com::max(seen as f64 / values.len() as f64, 1.0);
+                for (idx, value) in values.into_iter().enumerate() {
+                    let weight = weights.get(idx).cloned().unwrap_or(1.0);
+                    sketch.insert(value, each, weight * scale);
+                }
+                Timer::Sketch(sketch)
+            }
         };
-        buf
+    }
+}
+
+struct Shard {
+    time: HashMap<String, Timer>,
+    count: CountMap,
+    /// the gauges with the sequence of their last write
+    gauge: HashMap<String, (f64, usize)>,
+    timers: Arc<Timers>,
+    /// the write sequence of the gauges, shared by all the shards
+    seq: Arc<AtomicUsize>,
+    rng: Rng,
+}
+
+impl Shard {
+    fn new(timers: Arc<Timers>, seq: Arc<AtomicUsize>, rng: Rng) -> Shard {
+        Shard {
+            time: HashMap::new(),
+            count: CountMap::new(),
+            gauge: HashMap::new(),
+            timers,
+            seq,
+            rng,
+        }
     }
 
-    pub fn push(&self, item: Line) {
+    /// the metric name is only copied when it is new in this interval
+    fn push(&mut self, item: Line) {
         let Line { metric: m, kind: k } = item;
         match k {
             Time(v, c) => {
-                loop {
-                    if let Ok(mut time_guard) = self.time.try_lock() {
-                        let tinst = time_guard.entry(m).or_insert(TimeSet(Vec::new(), 0.0));
-                        tinst.0.push(v);
-                        tinst.1 += c;
-                        break;
-                    };
+                let weighted = self.timers.weighted;
+                if let Some(tinst) = self.time.get_mut(m) {
+                    tinst.push(v, c, weighted, &mut self.rng);
+                    return;
                 }
+                let mut tinst = self.timers.timer(m);
+                tinst.push(v, c, weighted, &mut self.rng);
+                self.time.insert(m.to_owned(), tinst);
             }
-            Count(v) => {
-                loop {
-                    if let Ok(mut count_guard) = self.count.try_lock() {
-                        let mut cinst = count_guard.entry(m).or_insert(ValueCount(0.0, 0.0));
-                        cinst.0 += v;
-                        cinst.1 += 1.0;
-                        break;
+            Merge(export) => {
+                let timer = match *export {
+                    Export::Samples(set) => {
+                        let mut reservoir = Reservoir::new(self.timers.reservoir.cap(m));
+                        reservoir.set = set;
+                        Timer::Exact(reservoir)
                     }
+                    Export::Sketch(sketch) => Timer::Sketch(sketch),
+                };
+                if let Some(tinst) = self.time.get_mut(m) {
+                    tinst.merge(timer, &mut self.rng);
+                    return;
+                }
+                // in the timer mode of this statsd, unless it is a sketch
+                let mut tinst = self.timers.timer(m);
+                tinst.merge(timer, &mut self.rng);
+                self.time.insert(m.to_owned(), tinst);
+            }
+            Count(v) => {
+                if let Some(cinst) = self.count.get_mut(m) {
+                    cinst.0 += v;
+                    cinst.1 += 1.0;
+                    return;
                 }
+                self.count.insert(m.to_owned(), ValueCount(v, 1.0));
             }
             Gauge(v) => {
-                loop {
-                    if let Ok(mut gauge_guard) = self.gauge.try_lock() {
-                        *gauge_guard.entry(m).or_insert(0.0) = v;
-                        break;
+                let seq = self.seq.fetch_add(1, Ordering::Relaxed);
+                if let Some(ginst) = self.gauge.get_mut(m) {
+                    *ginst = (v, seq);
+                    return;
+                }
+                self.gauge.insert(m.to_owned(), (v, seq));
+            }
+        }
+    }
+
+    /// merge other into self, a gauge set in both keeps the last written.
+    fn merge(&mut self, other: Shard) {
+        for (key, timer) in other.time {
+            match self.time.entry(key) {
+                Entry::Occupied(mut entry) => entry.get_mut().merge(timer, &mut self.rng),
+                Entry::Vacant(entry) => {
+                    entry.insert(timer);
+                }
+            }
+        }
+        for (key, ValueCount(value, count)) in other.count {
+            let cinst = self.count.entry(key).or_insert(ValueCount(0.0, 0.0));
+            cinst.0 += value;
+            cinst.1 += count;
+        }
+        for (key, gauge) in other.gauge {
+            match self.gauge.entry(key) {
+                Entry::Occupied(mut entry) => {
+                    if entry.get().1 < gauge.1 {
+                        entry.insert(gauge);
                     }
                 }
+                Entry::Vacant(entry) => {
+                    entry.insert(gauge);
+                }
             }
         }
     }
+}
 
-    pub fn truncate(&self) -> LightBuffer {
-        let mut time = self.time.lock().unwrap();
-        let mut count = self.count.lock().unwrap();
-        let mut gauge = self.gauge.lock().unwrap();
-        let now = now();
+impl MergeBuffer {
+    /// a buffer with one shard for each of the workers, and one more for
+    /// the reports of the adapter.
+    pub fn new(workers: usize, config: &Config) -> MergeBuffer {
+        let timers = Arc::new(Timers::new(config));
+        let seq = Arc::new(AtomicUsize::new(0));
+        let shards = (0..workers + 1)
+            .map(|idx| Mutex::new(Shard::new(timers.clone(), seq.clone(), Rng::new(idx as u64))))
+            .collect();
+        MergeBuffer { shards, seq }
+    }
 
-        let mut ntime = TimeMap::new();
-        mem::swap(&mut ntime, time.deref_mut());
-        mem::drop(time);
+    /// push by the worker, which only locks its own shard.
+    pub fn push(&self, worker: usize, item: Line) {
+        self.shards[worker].lock().unwrap().push(item);
+    }
 
-        let mut ncount = CountMap::new();
-        mem::swap(&mut ncount, count.deref_mut());
-        mem::drop(count);
+    /// push the self metrics of the adapter.
+    pub fn report(&self, item: Line) {
+        self.shards[self.shards.len() - 1].lock().unwrap().push(item);
+    }
 
-        let mut ngauge = GaugeMap::new();
-        mem::swap(&mut ngauge, gauge.deref_mut());
-        mem::drop(gauge);
+    /// take all the metrics out, timestamp is the flush boundary.
+    pub fn truncate(&self, timestamp: u64, config: &Config) -> LightBuffer {
+        // hold only one shard at a time, the other workers keep pushing,
+        // and the reloaded timer config takes effect in the next interval
+        let timers = Arc::new(Timers::new(config));
+        let mut merged = Shard::new(timers.clone(),
+                                    self.seq.clone(),
+                                    Rng::new(self.shards.len() as u64));
+        for shard in &self.shards {
+            let mut shard = shard.lock().unwrap();
+            let fresh = Shard::new(timers.clone(), self.seq.clone(), shard.rng.clone());
+            let taken = mem::replace(shard.deref_mut(), fresh);
+            merged.merge(taken);
+        }
+        let Shard { time, count: ncount, gauge, .. } = merged;
+        let ngauge: GaugeData = gauge.into_iter().map(|(key, (value, _))| (key, value)).collect();
+        let mut ntime = TimeMap::new();
+        let mut nsketch = SketchMap::new();
+        for (key, timer) in time {
+            match timer {
+                Timer::Exact(reservoir) => {
+                    ntime.insert(key, reservoir.set);
+                }
+                Timer::Sketch(sketch) => {
+                    nsketch.insert(key, sketch);
+                }
+            }
+        }
 
         debug!("get a {} timer, {} counter, {} gauger",
-               ntime.len(),
+               ntime.len() + nsketch.len(),
                ncount.len(),
                ngauge.len());
 
-        let time_data = LightBuffer::caculate_time(ntime);
+        let mut time_data = LightBuffer::caculate_time(&mut ntime, config);
+        time_data.extend(LightBuffer::caculate_sketch(&nsketch, config));
         LightBuffer {
-            timestamp: now,
+            timestamp,
             time: time_data,
+            samples: ntime,
+            sketches: nsketch,
             count: ncount,
             gauge: ngauge,
         }
@@ -450,8 +968,10 @@ pub type Result<T> = result::Result<T, StatsdError>;
 pub enum StatsdError {
     WrongLine,
     UnknownKind(String),
+    /// a sample rate out of (0, 1]
+    WrongRate(f64),
     ParseFloatError(ParseFloatError),
-    FromUtf8Error(FromUtf8Error),
+    Utf8Error(Utf8Error),
     IoError(Error),
 }
 
@@ -467,18 +987,21 @@ impl From<ParseFloatError> for StatsdError {
     }
 }
 
-impl From<FromUtf8Error> for StatsdError {
-    fn from(oe: FromUtf8Error) -> StatsdError {
-        StatsdError::FromUtf8Error(oe)
+impl From<Utf8Error> for StatsdError {
+    fn from(oe: Utf8Error) -> StatsdError {
+        StatsdError::Utf8Error(oe)
     }
 }
 
 pub struct Adapter;
 
 impl Adapter {
-    pub fn run(merge_buffer: &MergeBuffer) {
-        let mut sender = BackEndSender::default();
+    pub fn run(idx: usize,
+               merge_buffer: &MergeBuffer,
+               shared: Arc<SharedConfig>,
+               shutdown: &Shutdown) {
+        let mut sender = BackEndSender::new(shared);
         debug!("start an adaptor");
-        sender.serve(merge_buffer);
+        sender.serve(idx, merge_buffer, shutdown);
     }
 }
@@ -0,0 +1,340 @@
+use config::Config;
+use super::Sketch;
+
+/// how the boundary of a threshold is read from the sorted values
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Method {
+    /// the value at the rounded rank, as the stock statsd
+    NearestRank,
+    /// interpolate between the two closest ranks, as numpy and excel
+    Linear,
+}
+
+impl Method {
+    pub fn new(config: &Config) -> Method {
+        match &config.percentile_method[..] {
+            "linear" => Method::Linear,
+            _ => Method::NearestRank,
+        }
+    }
+}
+
+/// the sorted values of a timer, exact or approximated by a sketch. The
+/// ranks start from 1.
+pub trait Ranks {
+    /// the number of values
+    fn len(&self) -> f64;
+    /// the value of the rank in [1, len]
+    fn value_at(&self, rank: f64) -> f64;
+    /// the sum of the values with the rank in (lo, hi]
+    fn sum_between(&self, lo: f64, hi: f64) -> f64;
+
+    fn min(&self) -> f64 {
+        self.value_at(1.0)
+    }
+
+    fn max(&self) -> f64 {
+        self.value_at(self.len())
+    }
+
+    fn sum(&self) -> f64 {
+        self.sum_between(0.0, self.len())
+    }
+}
+
+/// Sorted is the sorted samples with the cumulative sums, a weighted
+/// sample takes the ranks of its weight.
+pub struct Sorted<'a> {
+    values: &'a [f64],
+    /// the weight of the first n values at n, empty when unweighted
+    ranks: Vec<f64>,
+    /// the weighted sum of the first n values at n
+    cumulative: Vec<f64>,
+}
+
+impl<'a> Sorted<'a> {
+    /// values must be sorted and not empty, the weights are in the same
+    /// order or empty.
+    pub fn new(values: &'a [f64], weights: &[f64]) -> Sorted<'a> {
+        let mut ranks = if weights.is_empty() { Vec::new() } else { vec![0.0] };
+        let mut cumulative = Vec::with_capacity(values.len() + 1);
+        cumulative.push(0.0);
+        let (mut rank, mut latest) = (0.0, 0.0);
+        for (idx, &value) in values.iter().enumerate() {
+            let weight = weights.get(idx).cloned().unwrap_or(1.0);
+            rank += weight;
+            latest += value * weight;
+            cumulative.push(latest);
+            if !weights.is_empty() {
+                ranks.push(rank);
+            }
+        }
+        Sorted {
+            values,
+            ranks,
+            cumulative,
+        }
+    }
+
+    /// the sum of the values with the rank in (0, rank]
+    fn sum_to(&self, rank: f64) -> f64 {
+        if self.ranks.is_empty() {
+            return self.cumulative[rank as usize];
+        }
+        // the values which fit in the rank as a whole, and a part of the next
+        let idx = self.ranks.partition_point(|&end| end <= rank) - 1;
+        match self.values.get(idx) {
+            Some(value) => self.cumulative[idx] + (rank - self.ranks[idx]) * value,
+            None => self.cumulative[idx],
+        }
+    }
+}
+
+impl<'a> Ranks for Sorted<'a> {
+    fn len(&self) -> f64 {
+        self.ranks.last().cloned().unwrap_or(self.values.len() as f64)
+    }
+
+    fn value_at(&self, rank: f64) -> f64 {
+        let idx = if self.ranks.is_empty() {
+            rank as usize
+        } else {
+            self.ranks.partition_point(|&end| end < rank)
+        };
+        self.values[idx.max(1).min(self.values.len()) - 1]
+    }
+
+    fn sum_between(&self, lo: f64, hi: f64) -> f64 {
+        self.sum_to(hi) - self.sum_to(lo)
+    }
+}
+
+/// the ranks of a sketch, with the exact min, max and sum.
+impl Ranks for Sketch {
+    fn len(&self) -> f64 {
+        self.len
+    }
+
+    fn value_at(&self, rank: f64) -> f64 {
+        Sketch::value_at(self, rank)
+    }
+
+    fn sum_between(&self, lo: f64, hi: f64) -> f64 {
+        Sketch::sum_between(self, lo, hi)
+    }
+
+    fn min(&self) -> f64 {
+        self.min
+    }
+
+    fn max(&self) -> f64 {
+        self.max
+    }
+
+    fn sum(&self) -> f64 {
+        self.sum
+    }
+}
+
+/// the value at the percentile p in [0, 100].
+pub fn percentile<R: Ranks>(ranks: &R, p: f64, method: Method) -> f64 {
+    let len = ranks.len();
+    match method {
+        Method::NearestRank => ranks.value_at((p / 100.0 * len).round().max(1.0)),
+        Method::Linear => {
+            let rank = (len - 1.0) * p / 100.0 + 1.0;
+            let low = rank.floor();
+            let (lower, upper) = (ranks.value_at(low), ranks.value_at((low + 1.0).min(len)));
+            lower + (rank - low) * (upper - lower)
+        }
+    }
+}
+
+/// the middle value, or the mean of the two middle ones for an even count.
+pub fn median<R: Ranks>(ranks: &R) -> f64 {
+    let len = ranks.len();
+    let mid = (len / 2.0).floor();
+    if len % 2.0 == 1.0 {
+        ranks.value_at(mid + 1.0)
+    } else {
+        (ranks.value_at(mid) + ranks.value_at(mid + 1.0)) / 2.0
+    }
+}
+
+/// the stats of the lowest values in a threshold, or the highest ones for
+/// a negative threshold.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Threshold {
+    pub count: f64,
+    pub sum: f64,
+    pub mean: f64,
+    /// the upper bound of the lowest values, or the lower bound of the
+    /// highest ones
+    pub boundary: f64,
+}
+
+/// the stats of the threshold in (0, 100] or [-100, 0), None when the
+/// threshold takes no value. The count is rounded as the stock statsd.
+pub fn threshold<R: Ranks>(ranks: &R, threshold: f64, method: Method) -> Option<Threshold> {
+    let len = ranks.len();
+    if len <= 1.0 {
+        let value = ranks.value_at(1.0);
+        return Some(Threshold {
+            count: len,
+            sum: value,
+            mean: value,
+            boundary: value,
+        });
+    }
+    let count = (threshold.abs() / 100.0 * len).round();
+    if count == 0.0 {
+        return None;
+    }
+    let (sum, boundary) = if threshold > 0.0 {
+        let boundary = match method {
+            Method::NearestRank => ranks.value_at(count),
+            Method::Linear => percentile(ranks, threshold, method),
+        };
+        (ranks.sum_between(0.0, count), boundary)
+    } else {
+        let boundary = match method {
+            Method::NearestRank => ranks.value_at(len - count + 1.0),
+            Method::Linear => percentile(ranks, 100.0 + threshold, method),
+        };
+        (ranks.sum_between(len - count, len), boundary)
+    };
+    Some(Threshold {
+        count,
+        sum,
+        mean: sum / count,
+        boundary,
+    })
+}
+
+/// the key suffix of a threshold, eg: "90" or "99_9" for 99.9.
+pub fn suffix(threshold: f64) -> String {
+    threshold.abs().to_string().replace('.', "_")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use worker::reservoir::Rng;
+
+    /// the stock statsd, with the cumulative sums indexed from 0
+    fn reference_threshold(values: &[f64], pct: f64) -> Option<Threshold> {
+        let count = values.len();
+        let cumulative: Vec<f64> = values.iter()
+            .scan(0.0, |sum, &value| {
+                *sum += value;
+                Some(*sum)
+            })
+            .collect();
+        if count == 1 {
+            return Some(Threshold {
+                count: 1.0,
+                sum: values[0],
+                mean: values[0],
+                boundary: values[0],
+            });
+        }
+        let num = (pct.abs() / 100.0 * count as f64).round() as usize;
+        if num == 0 {
+            return None;
+        }
+        let (boundary, sum) = if pct > 0.0 {
+            (values[num - 1], cumulative[num - 1])
+        } else if num == count {
+            (values[0], cumulative[count - 1])
+        } else {
+            (values[count - num], cumulative[count - 1] - cumulative[count - num - 1])
+        };
+        Some(Threshold {
+            count: num as f64,
+            sum,
+            mean: sum / num as f64,
+            boundary,
+        })
+    }
+
+    /// numpy.percentile with the default linear interpolation
+    fn reference_linear(values: &[f64], p: f64) -> f64 {
+        let pos = p / 100.0 * (values.len() - 1) as f64;
+        let low = pos.floor() as usize;
+        let high = (low + 1).min(values.len() - 1);
+        values[low] + (pos - low as f64) * (values[high] - values[low])
+    }
+
+    fn close(a: f64, b: f64) -> bool {
+        (a - b).abs() <= 1e-9 * a.abs().max(b.abs()).max(1.0)
+    }
+
+    #[test]
+    fn test_median() {
+        assert_eq!(median(&Sorted::new(&[1.0], &[])), 1.0);
+        assert_eq!(median(&Sorted::new(&[1.0, 2.0], &[])), 1.5);
+        assert_eq!(median(&Sorted::new(&[1.0, 2.0, 10.0], &[])), 2.0);
+        assert_eq!(median(&Sorted::new(&[1.0, 2.0, 3.0, 10.0], &[])), 2.5);
+    }
+
+    #[test]
+    fn test_suffix() {
+        assert_eq!(suffix(90.0), "90");
+        assert_eq!(suffix(-99.9), "99_9");
+    }
+
+    #[test]
+    fn test_threshold_match_reference() {
+        let mut rng = Rng::new(7);
+        for _ in 0..2000 {
+            let len = rng.below(50) as usize + 1;
+            let mut values: Vec<f64> = (0..len).map(|_| rng.below(100) as f64).collect();
+            values.sort_by(|a, b| a.partial_cmp(b).unwrap());
+            let sorted = Sorted::new(&values, &[]);
+            // a tenth of a percent, positive or negative
+            let pct = (rng.below(1000) + 1) as f64 / 10.0 *
+                      if rng.below(2) == 0 { 1.0 } else { -1.0 };
+
+            let expect = reference_threshold(&values, pct);
+            let actual = threshold(&sorted, pct, Method::NearestRank);
+            assert_eq!(actual, expect, "{:?} at {}", values, pct);
+
+            let linear = percentile(&sorted, pct.abs(), Method::Linear);
+            assert!(close(linear, reference_linear(&values, pct.abs())),
+                    "{:?} at {}",
+                    values,
+                    pct);
+            assert!(values[0] <= linear && linear <= values[len - 1]);
+            assert!(close(median(&sorted), reference_linear(&values, 50.0)));
+        }
+    }
+    #[test]
+    fn test_weighted_match_repeated() {
+        let mut rng = Rng::new(11);
+        for _ in 0..500 {
+            let len = rng.below(20) as usize + 1;
+            let mut values: Vec<f64> = (0..len).map(|_| rng.below(100) as f64).collect();
+            values.sort_by(|a, b| a.partial_cmp(b).unwrap());
+            let weights: Vec<f64> = (0..len).map(|_| (rng.below(4) + 1) as f64).collect();
+            // a value of weight n stands for n equal ones
+            let repeated: Vec<f64> = values.iter()
+                .zip(&weights)
+                .flat_map(|(&value, &weight)| vec![value; weight as usize])
+                .collect();
+            let (weighted, plain) = (Sorted::new(&values, &weights), Sorted::new(&repeated, &[]));
+            assert_eq!(weighted.len(), plain.len());
+            assert_eq!(median(&weighted), median(&plain));
+            for &pct in &[1.0, 50.0, 90.0, 99.9, -10.0, -100.0] {
+                let expect = threshold(&plain, pct, Method::NearestRank);
+                let actual = threshold(&weighted, pct, Method::NearestRank);
+                match (actual, expect) {
+                    (Some(actual), Some(expect)) => {
+                        assert_eq!((actual.count, actual.boundary), (expect.count, expect.boundary));
+                        assert!(close(actual.sum, expect.sum), "{:?} at {}", values, pct);
+                    }
+                    (actual, expect) => assert_eq!(actual, expect),
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,346 @@
+use std::io;
+use std::net;
+use std::os::unix::io::AsRawFd;
+
+use futures::{task, Async, Future, Poll};
+use libc;
+use mio::{self, Evented, PollOpt, Ready, Token};
+use mio::unix::EventedFd;
+use tokio_core::reactor::{Handle, PollEvented};
+
+use super::{Line, Packet, Sink, StatsdError, MAX_DATAGRAM, RECV_BUDGET};
+
+/// RawSocket registers the std socket to the reactor by its fd, so the
+/// datagrams can be read by `recvmmsg` directly.
+struct RawSocket(net::UdpSocket);
+
+impl Evented for RawSocket {
+    fn register(&self,
+                poll: &mio::Poll,
+                token: Token,
+                interest: Ready,
+                opts: PollOpt)
+                -> io::Result<()> {
+        EventedFd(&self.0.as_raw_fd()).register(poll, token, interest, opts)
+    }
+
+    fn reregister(&self,
+                  poll: &mio::Poll,
+                  token: Token,
+                  interest: Ready,
+                  opts: PollOpt)
+                  -> io::Result<()> {
+        EventedFd(&self.0.as_raw_fd()).reregister(poll, token, interest, opts)
+    }
+
+    fn deregister(&self, poll: &mio::Poll) -> io::Result<()> {
+        EventedFd(&self.0.as_raw_fd()).deregister(poll)
+    }
+}
+
+/// Recv receives a batch of datagrams at a time into the preallocated
+/// buffers, and the lines are parsed in place without copying.
+pub struct Recv<S> {
+    socket: PollEvented<RawSocket>,
+    sink: S,
+    batch: Batch,
+    /// the kernel drops seen last time
+    drops: u32,
+}
+
+impl<S: Sink> Recv<S> {
+    pub fn new(socket: net::UdpSocket,
+               sink: S,
+               batch: usize,
+               handle: &Handle)
+               -> io::Result<Recv<S>> {
+        socket.set_nonblocking(true)?;
+        if let Err(err) = enable_drops(&socket) {
+            warn!("worker: can't count the kernel drops, error: {}", err);
+        }
+        Ok(Recv {
+            socket: PollEvented::new(RawSocket(socket), handle)?,
+            sink,
+            batch: Batch::new(batch),
+            drops: 0,
+        })
+    }
+}
+
+impl<S: Sink> Future for Recv<S> {
+    type Item = ();
+    type Error = StatsdError;
+
+    fn poll(&mut self) -> Poll<(), StatsdError> {
+        for _ in 0..RECV_BUDGET {
+            if let Async::NotReady = self.socket.poll_read() {
+                return Ok(Async::NotReady);
+            }
+            let num = match self.batch.recv(&self.socket.get_ref().0) {
+                Ok(num) => num,
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {
+                    self.socket.need_read();
+                    return Ok(Async::NotReady);
+                }
+                Err(ref err) if is_transient(err) => {
+                    warn!("worker: receive error {}, keep receiving", err);
+                    continue;
+                }
+                Err(err) => return Err(StatsdError::from(err)),
+            };
+            debug!("get {} new packets", num);
+            for idx in 0..num {
+                let packet = self.batch.packet(idx);
+                for line in Packet::new(packet) {
+                    self.sink.push(line);
+                }
+                self.sink.report(Line::report("statsd.recv", packet.len()));
+            }
+            // the drops counter of the socket only grows, wrapping at u32
+            if let Some(drops) = self.batch.drops() {
+                let delta = drops.wrapping_sub(self.drops);
+                self.drops = drops;
+                if delta > 0 {
+                    self.sink.report(Line::report("statsd.recv.drops", delta as usize));
+                }
+            }
+            self.sink.flush();
+        }
+        // yield to let the shutdown be checked when the socket keeps busy
+        task::current().notify();
+        Ok(Async::NotReady)
+    }
+}
+
+/// the errors of a receive which the next one may not have, eg: a signal
+/// or the kernel short of memory for a moment.
+fn is_transient(err: &io::Error) -> bool {
+    match err.kind() {
+        io::ErrorKind::Interrupted |
+        io::ErrorKind::ConnectionRefused |
+        io::ErrorKind::ConnectionReset |
+        io::ErrorKind::OutOfMemory => true,
+        _ => err.raw_os_error() == Some(libc::ENOBUFS),
+    }
+}
+
+#[cfg(target_os = "linux")]
+use self::linux::{Batch, enable_drops};
+#[cfg(not(target_os = "linux"))]
+use self::fallback::{Batch, enable_drops};
+
+#[cfg(target_os = "linux")]
+mod linux {
+    use std::io;
+    use std::mem;
+    use std::net;
+    use std::os::unix::io::AsRawFd;
+    use std::ptr;
+
+    use libc;
+
+    use super::MAX_DATAGRAM;
+
+    /// room for the cmsg of SO_RXQ_OVFL, as u64 to keep it aligned
+    const CMSG_WORDS: usize = 4;
+
+    /// let the kernel attach the drops counter of the socket to the
+    /// received datagrams.
+    pub fn enable_drops(socket: &net::UdpSocket) -> io::Result<()> {
+        let on: libc::c_int = 1;
+        let ret = unsafe {
+            libc::setsockopt(socket.as_raw_fd(),
+                             libc::SOL_SOCKET,
+                             libc::SO_RXQ_OVFL,
+                             &on as *const _ as *const libc::c_void,
+                             mem::size_of::<libc::c_int>() as libc::socklen_t)
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// Batch is the buffers and headers of one `recvmmsg`, the headers
+    /// point into the heap of the vectors, which never grow.
+    pub struct Batch {
+        bufs: Vec<Vec<u8>>,
+        /// only owned for the headers to point into
+        _iovecs: Vec<libc::iovec>,
+        _cmsgs: Vec<[u64; CMSG_WORDS]>,
+        msgs: Vec<libc::mmsghdr>,
+        num: usize,
+    }
+
+    impl Batch {
+        pub fn new(size: usize) -> Batch {
+            let mut bufs: Vec<Vec<u8>> = (0..size).map(|_| vec![0; MAX_DATAGRAM]).collect();
+            let mut iovecs: Vec<libc::iovec> = bufs.iter_mut()
+                .map(|buf| {
+                    libc::iovec {
+                        iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+                        iov_len: buf.len(),
+                    }
+                })
+                .collect();
+            let mut cmsgs = vec![[0u64; CMSG_WORDS]; size];
+            let msgs = iovecs.iter_mut()
+                .zip(cmsgs.iter_mut())
+                .map(|(iovec, cmsg)| {
+                    let mut msg: libc::mmsghdr = unsafe { mem::zeroed() };
+                    msg.msg_hdr.msg_iov = iovec;
+                    msg.msg_hdr.msg_iovlen = 1;
+                    msg.msg_hdr.msg_control = cmsg.as_mut_ptr() as *mut libc::c_void;
+                    msg
+                })
+                .collect();
+            Batch {
+                bufs,
+                _iovecs: iovecs,
+                _cmsgs: cmsgs,
+                msgs,
+                num: 0,
+            }
+        }
+
+        /// receive as many datagrams as the batch holds in one syscall.
+        pub fn recv(&mut self, socket: &net::UdpSocket) -> io::Result<usize> {
+            for msg in &mut self.msgs {
+                // the kernel shrinks them to what it wrote
+                msg.msg_hdr.msg_controllen = mem::size_of::<[u64; CMSG_WORDS]>() as _;
+                msg.msg_hdr.msg_flags = 0;
+                msg.msg_len = 0;
+            }
+            let ret = unsafe {
+                libc::recvmmsg(socket.as_raw_fd(),
+                               self.msgs.as_mut_ptr(),
+                               self.msgs.len() as libc::c_uint,
+                               libc::MSG_DONTWAIT,
+                               ptr::null_mut())
+            };
+            if ret < 0 {
+                self.num = 0;
+                return Err(io::Error::last_os_error());
+            }
+            self.num = ret as usize;
+            for msg in &self.msgs[..self.num] {
+                if msg.msg_hdr.msg_flags & libc::MSG_TRUNC != 0 {
+                    warn!("worker: a datagram is truncated to {} bytes", MAX_DATAGRAM);
+                }
+            }
+            Ok(self.num)
+        }
+
+        pub fn packet(&self, idx: usize) -> &[u8] {
+            &self.bufs[idx][..self.msgs[idx].msg_len as usize]
+        }
+
+        /// the drops counter of the socket attached to the last datagram of
+        /// the batch, the kernel omits it until the first drop.
+        pub fn drops(&self) -> Option<u32> {
+            self.msgs[..self.num].iter().rev().filter_map(|msg| rxq_ovfl(&msg.msg_hdr)).next()
+        }
+    }
+
+    fn rxq_ovfl(hdr: &libc::msghdr) -> Option<u32> {
+        unsafe {
+            let mut cmsg = libc::CMSG_FIRSTHDR(hdr);
+            while !cmsg.is_null() {
+                if (*cmsg).cmsg_level == libc::SOL_SOCKET &&
+                   (*cmsg).cmsg_type == libc::SO_RXQ_OVFL {
+                    return Some(ptr::read_unaligned(libc::CMSG_DATA(cmsg) as *const u32));
+                }
+                cmsg = libc::CMSG_NXTHDR(hdr, cmsg);
+            }
+        }
+        None
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod fallback {
+    use std::io;
+    use std::net;
+
+    use super::MAX_DATAGRAM;
+
+    pub fn enable_drops(_: &net::UdpSocket) -> io::Result<()> {
+        Err(io::Error::new(io::ErrorKind::Other, "SO_RXQ_OVFL is linux only"))
+    }
+
+    /// Batch holds one datagram at a time without `recvmmsg`.
+    pub struct Batch {
+        buf: Vec<u8>,
+        size: usize,
+    }
+
+    impl Batch {
+        pub fn new(_: usize) -> Batch {
+            Batch {
+                buf: vec![0; MAX_DATAGRAM],
+                size: 0,
+            }
+        }
+
+        pub fn recv(&mut self, socket: &net::UdpSocket) -> io::Result<usize> {
+            self.size = socket.recv_from(&mut self.buf)?.0;
+            Ok(1)
+        }
+
+        pub fn packet(&self, _: usize) -> &[u8] {
+            &self.buf[..self.size]
+        }
+
+        pub fn drops(&self) -> Option<u32> {
+            None
+        }
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod test {
+    use super::*;
+    use std::thread;
+    use std::time::Duration;
+    use net2::UdpSocketExt;
+
+    #[test]
+    fn test_transient_errors() {
+        for &code in &[libc::EINTR, libc::ENOBUFS, libc::ENOMEM, libc::ECONNREFUSED] {
+            assert!(is_transient(&io::Error::from_raw_os_error(code)), "{}", code);
+        }
+        for &code in &[libc::EBADF, libc::ENOTSOCK, libc::EINVAL, libc::EFAULT] {
+            assert!(!is_transient(&io::Error::from_raw_os_error(code)), "{}", code);
+        }
+    }
+
+    #[test]
+    fn test_batch_recv_many_and_count_drops() {
+        let socket = net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        enable_drops(&socket).unwrap();
+        let client = net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = socket.local_addr().unwrap();
+        for num in 0..3 {
+            client.send_to(format!("a:{}|c", num).as_bytes(), addr).unwrap();
+        }
+        thread::sleep(Duration::from_millis(50));
+
+        let mut batch = Batch::new(8);
+        assert_eq!(batch.recv(&socket).unwrap(), 3);
+        assert_eq!(batch.packet(2), b"a:2|c");
+        assert_eq!(batch.drops(), None);
+
+        // the kernel rounds it up to its minimum, a few small datagrams
+        socket.set_recv_buffer_size(1).unwrap();
+        for _ in 0..1000 {
+            client.send_to(b"a:1|c", addr).unwrap();
+        }
+        thread::sleep(Duration::from_millis(50));
+        while batch.recv(&socket).is_ok() {}
+        // the counter comes with the datagrams queued after the drops
+        client.send_to(b"a:1|c", addr).unwrap();
+        thread::sleep(Duration::from_millis(50));
+        assert_eq!(batch.recv(&socket).unwrap(), 1);
+        assert!(batch.drops().unwrap() > 0);
+    }
+}
@@ -0,0 +1,156 @@
+use std::mem;
+
+use com;
+use super::TimeSet;
+
+/// xorshift64*, good enough to pick the samples to keep.
+#[derive(Clone, Debug)]
+pub struct Rng(u64);
+
+impl Rng {
+    pub fn new(seed: u64) -> Rng {
+        let nanos = com::since_epoch().subsec_nanos() as u64;
+        // the state must never be 0
+        Rng((seed.wrapping_mul(0x9e3779b97f4a7c15) ^ nanos) | 1)
+    }
+
+    /// a random number in [0, n)
+    pub fn below(&mut self, n: u64) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545f4914f6cdd1d) % n
+    }
+}
+
+/// Reservoir is a `TimeSet` of a shard with the cap of its samples.
+#[derive(Debug)]
+pub struct Reservoir {
+    pub set: TimeSet,
+    pub cap: usize,
+}
+
+impl Reservoir {
+    pub fn new(cap: usize) -> Reservoir {
+        Reservoir {
+            set: TimeSet(Vec::new(), 0.0, 0, Vec::new()),
+            cap,
+        }
+    }
+
+    /// keep the value by reservoir sampling once the cap is reached, the
+    /// count is always exact. A weighted value keeps its count as weight.
+    pub fn push(&mut self, value: f64, count: f64, weighted: bool, rng: &mut Rng) {
+        let TimeSet(ref mut values, ref mut total, ref mut seen, ref mut weights) = self.set;
+        *total += count;
+        *seen += 1;
+        if self.cap == 0 || values.len() < self.cap {
+            values.push(value);
+            if weighted {
+                weights.push(count);
+            }
+            return;
+        }
+        let idx = rng.below(*seen) as usize;
+        if idx < self.cap {
+            values[idx] = value;
+            if weighted {
+                weights[idx] = count;
+            }
+        }
+    }
+
+    /// merge other into self, a capped result is a uniform sample of the
+    /// values seen by both.
+    pub fn merge(&mut self, other: Reservoir, rng: &mut Rng) {
+        let mut other_set = other.set;
+        // weigh both sides when either is weighted
+        let weighted = !self.set.3.is_empty() || !other_set.3.is_empty();
+        if weighted {
+            weigh(&mut self.set);
+            weigh(&mut other_set);
+        }
+        let TimeSet(theirs, count, their_seen, their_weights) = other_set;
+        let cap = if self.cap == 0 { other.cap } else { self.cap };
+        self.cap = cap;
+        self.set.1 += count;
+        let ours_seen = self.set.2;
+        self.set.2 += their_seen;
+        if cap == 0 || self.set.0.len() + theirs.len() <= cap {
+            self.set.0.extend(theirs);
+            self.set.3.extend(their_weights);
+            return;
+        }
+        let mut ours = pairs(mem::replace(&mut self.set.0, Vec::with_capacity(cap)),
+                             mem::take(&mut self.set.3));
+        let mut theirs = pairs(theirs, their_weights);
+        // draw without replacement from the values both sides stand for
+        let (mut left_ours, mut left_theirs) = (ours_seen, their_seen);
+        while self.set.0.len() < cap && !(ours.is_empty() && theirs.is_empty()) {
+            let pick_ours = theirs.is_empty() ||
+                            (!ours.is_empty() &&
+                             rng.below(left_ours + left_theirs) < left_ours);
+            let (from, left) = if pick_ours {
+                (&mut ours, &mut left_ours)
+            } else {
+                (&mut theirs, &mut left_theirs)
+            };
+            let idx = rng.below(from.len() as u64) as usize;
+            let (value, weight) = from.swap_remove(idx);
+            self.set.0.push(value);
+            if weighted {
+                self.set.3.push(weight);
+            }
+            *left = left.saturating_sub(1);
+        }
+    }
+}
+
+/// weigh the samples of an unweighted set as 1 each.
+fn weigh(set: &mut TimeSet) {
+    if set.3.is_empty() {
+        set.3 = vec![1.0; set.0.len()];
+    }
+}
+
+/// the values with their weights, 1 each when unweighted.
+fn pairs(values: Vec<f64>, weights: Vec<f64>) -> Vec<(f64, f64)> {
+    if weights.is_empty() {
+        return values.into_iter().map(|value| (value, 1.0)).collect();
+    }
+    values.into_iter().zip(weights).collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_reservoir_keep_count_and_bound_samples() {
+        let mut rng = Rng::new(1);
+        let (mut a, mut b) = (Reservoir::new(100), Reservoir::new(100));
+        for num in 0..10_000 {
+            a.push(num as f64, 1.0, false, &mut rng);
+            b.push((num + 10_000) as f64, 2.0, false, &mut rng);
+        }
+        assert_eq!(a.set.0.len(), 100);
+        assert_eq!((a.set.1, a.set.2), (10_000.0, 10_000));
+
+        a.merge(b, &mut rng);
+        assert_eq!(a.set.0.len(), 100);
+        assert_eq!((a.set.1, a.set.2), (30_000.0, 20_000));
+        // a uniform sample of 0..20000 keeps the mean near 10000
+        let mean = a.set.0.iter().sum::<f64>() / 100.0;
+        assert!((mean - 10_000.0).abs() < 3_000.0, "mean {}", mean);
+    }
+
+    #[test]
+    fn test_reservoir_no_cap_keep_all() {
+        let mut rng = Rng::new(1);
+        let (mut a, mut b) = (Reservoir::new(0), Reservoir::new(0));
+        a.push(1.0, 1.0, false, &mut rng);
+        b.push(2.0, 1.0, false, &mut rng);
+        a.merge(b, &mut rng);
+        assert_eq!(a.set.0, vec![1.0, 2.0]);
+    }
+}
@@ -0,0 +1,582 @@
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use std::f64::consts::PI;
+use std::mem;
+
+use config::Config;
+use super::export;
+
+/// the max buckets of each sign of a ddsketch, the lowest ones are
+/// collapsed beyond it
+const MAX_BUCKETS: usize = 2048;
+/// the values closer to 0 than it go to the zero bucket of a ddsketch
+const MIN_INDEXABLE: f64 = 1e-9;
+/// the compression of a t-digest, a decoded one is clamped into it
+pub const COMPRESSION_RANGE: (f64, f64) = (10.0, 10_000.0);
+/// the relative accuracy of a ddsketch, a decoded one must be in it
+pub const ACCURACY_RANGE: (f64, f64) = (1e-4, 0.5);
+
+/// how the timers keep their samples in an interval
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TimerMode {
+    /// every sample, or a reservoir of them, sorted at flush
+    Exact,
+    /// a t-digest of the compression
+    TDigest(f64),
+    /// a ddsketch of the relative accuracy
+    DDSketch(f64),
+}
+
+impl TimerMode {
+    pub fn new(config: &Config) -> TimerMode {
+        match &config.timer_mode[..] {
+            "tdigest" => TimerMode::TDigest(config.tdigest_compression),
+            "ddsketch" => TimerMode::DDSketch(config.ddsketch_accuracy),
+            _ => TimerMode::Exact,
+        }
+    }
+}
+
+/// Sketch summarizes the samples of a timer in constant memory, the
+/// count, sum, min and max are exact while the ranks are approximate.
+#[derive(Clone, Debug)]
+pub struct Sketch {
+    /// the count weighted by the sample rates
+    pub count: f64,
+    /// the weight of the samples, the number of them unless weighted
+    pub len: f64,
+    /// the weighted sum of the samples
+    pub sum: f64,
+    pub min: f64,
+    pub max: f64,
+    summary: Summary,
+}
+
+#[derive(Clone, Debug)]
+enum Summary {
+    TDigest(TDigest),
+    DDSketch(DDSketch),
+}
+
+impl Sketch {
+    /// None for the exact mode.
+    pub fn new(mode: TimerMode) -> Option<Sketch> {
+        let summary = match mode {
+            TimerMode::Exact => return None,
+            TimerMode::TDigest(compression) => Summary::TDigest(TDigest::new(compression)),
+            TimerMode::DDSketch(accuracy) => Summary::DDSketch(DDSketch::new(accuracy)),
+        };
+        Some(Sketch {
+            count: 0.0,
+            len: 0.0,
+            sum: 0.0,
+            min: f64::INFINITY,
+            max: f64::NEG_INFINITY,
+            summary,
+        })
+    }
+
+    /// a sample of weight n takes n ranks, as n equal samples.
+    pub fn insert(&mut self, value: f64, count: f64, weight: f64) {
+        self.count += count;
+        self.len += weight;
+        self.sum += value * weight;
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+        match self.summary {
+            Summary::TDigest(ref mut digest) => digest.insert(value, weight),
+            Summary::DDSketch(ref mut sketch) => sketch.insert(value, weight),
+        }
+    }
+
+    /// merge other into self, a sketch of another kind is merged by its
+    /// points.
+    pub fn merge(&mut self, other: Sketch) {
+        self.count += other.count;
+        self.len += other.len;
+        self.sum += other.sum;
+        self.min = self.min.min(other.min);
+        self.max = self.max.max(other.max);
+        match (&mut self.summary, other.summary) {
+            (&mut Summary::TDigest(ref mut ours), Summary::TDigest(theirs)) => ours.merge(theirs),
+            (&mut Summary::DDSketch(ref mut ours), Summary::DDSketch(ref theirs))
+                if ours.gamma == theirs.gamma => ours.merge(theirs),
+            (ours, theirs) => {
+                for (value, weight) in points_of(&theirs) {
+                    match *ours {
+                        Summary::TDigest(ref mut digest) => digest.insert(value, weight),
+                        Summary::DDSketch(ref mut sketch) => sketch.insert(value, weight),
+                    }
+                }
+            }
+        }
+    }
+
+    /// the sorted (value, weight) points, the weights add up to `len`.
+    pub fn points(&self) -> Vec<(f64, f64)> {
+        points_of(&self.summary)
+            .into_iter()
+            .map(|(value, weight)| (value.max(self.min).min(self.max), weight))
+            .collect()
+    }
+
+    /// the value of the rank in [1, len], the nth smallest one.
+    pub fn value_at(&self, rank: f64) -> f64 {
+        let mut cumulative = 0.0;
+        for (value, weight) in self.points() {
+            cumulative += weight;
+            if cumulative >= rank {
+                return value;
+            }
+        }
+        self.max
+    }
+
+    /// the sum of the values with the rank in (lo, hi].
+    pub fn sum_between(&self, lo: f64, hi: f64) -> f64 {
+        let mut cumulative = 0.0;
+        let mut sum = 0.0;
+        for (value, weight) in self.points() {
+            let (start, end) = (cumulative, cumulative + weight);
+            cumulative = end;
+            let overlap = end.min(hi) - start.max(lo);
+            if overlap > 0.0 {
+                sum += value * overlap;
+            }
+        }
+        sum
+    }
+}
+
+/// encode and decode a sketch as the payloads of the `|td` and `|dd` lines
+/// of the merge export, see `export`.
+impl Sketch {
+    /// the payloads of at most about max bytes each. Each one is a sketch
+    /// of its own points, with its share of the count and sum, so a lost
+    /// or late one only takes its points away.
+    pub fn encode(&self, max: usize) -> Vec<String> {
+        // the (value, weight) of the points in the order of the items
+        let (kind, param, zero, points, items): (_, _, _, Vec<_>, Vec<String>) =
+            match self.summary {
+                Summary::TDigest(ref digest) => {
+                    let points = digest.points();
+                    let items = points.iter()
+                        .map(|&(mean, weight)| format!("{}*{}", mean, weight))
+                        .collect();
+                    ("td", digest.compression, 0.0, points, items)
+                }
+                Summary::DDSketch(ref sketch) => {
+                    let positive = sketch.positive.iter().map(|(&idx, &weight)| (idx, weight));
+                    let negative = sketch.negative.iter().map(|(&idx, &weight)| (idx, weight));
+                    let points = positive.clone()
+                        .map(|(idx, weight)| (sketch.value(idx), weight))
+                        .chain(negative.clone().map(|(idx, weight)| (-sketch.value(idx), weight)))
+                        .collect();
+                    let items = positive.map(|(idx, weight)| format!("{}*{}", idx, weight))
+                        .chain(negative.map(|(idx, weight)| format!("n{}*{}", idx, weight)))
+                        .collect();
+                    ("dd", sketch.accuracy(), sketch.zero, points, items)
+                }
+            };
+        let clamp = |value: f64| value.max(self.min).min(self.max);
+        let extremes = |points: &[(f64, f64)], zero: f64| {
+            points.iter()
+                .map(|&(value, _)| value)
+                .chain(if zero > 0.0 { Some(0.0) } else { None })
+                .fold((f64::INFINITY, f64::NEG_INFINITY),
+                      |(lo, hi), value| (lo.min(value), hi.max(value)))
+        };
+        // the exact min and max go with the parts of the extreme points
+        let (lowest, highest) = extremes(&points, zero);
+        // leave room for the stats
+        let parts = export::chunks(&items, max.saturating_sub(128));
+        let (mut start, mut count_left, mut sum_left) = (0, self.count, self.sum);
+        parts.iter()
+            .enumerate()
+            .map(|(idx, part)| {
+                let points = &points[start..start + part.len()];
+                start += part.len();
+                // the zero bucket goes with the first part
+                let zero = if idx == 0 { zero } else { 0.0 };
+                let len = points.iter().map(|&(_, weight)| weight).sum::<f64>() + zero;
+                let (count, sum) = if idx + 1 == parts.len() {
+                    (count_left, sum_left)
+                } else {
+                    let sum = points.iter().map(|&(value, weight)| clamp(value) * weight).sum();
+                    (self.count * len / self.len, sum)
+                };
+                count_left -= count;
+                sum_left -= sum;
+                let (lo, hi) = extremes(points, zero);
+                let min = if lo == lowest { self.min } else { clamp(lo) };
+                let max = if hi == highest { self.max } else { clamp(hi) };
+                format!("{};{};{};{};{};{};{};{}|{}",
+                        count,
+                        len,
+                        sum,
+                        min,
+                        max,
+                        param,
+                        zero,
+                        part.join(","),
+                        kind)
+            })
+            .collect()
+    }
+
+    pub fn decode(kind: &str, payload: &str) -> Option<Sketch> {
+        let fields: Vec<_> = payload.split(';').collect();
+        if fields.len() != 8 {
+            return None;
+        }
+        let mut nums = fields[..7].iter().map(|field| field.parse::<f64>().ok());
+        let mut next = || nums.next().and_then(|num| num);
+        let (count, len, sum, min, max, param, zero) =
+            (next()?, next()?, next()?, next()?, next()?, next()?, next()?);
+        // a line from the wire must neither break the stats nor the memory
+        if ![count, len, sum, param, zero].iter().all(|num| num.is_finite()) ||
+           count < 0.0 || len < 0.0 || zero < 0.0 {
+            return None;
+        }
+        // a sketch without points has no stats to merge
+        if len == 0.0 || !(min.is_finite() && max.is_finite() && min <= max) {
+            return None;
+        }
+        let items = fields[7].split(',').filter(|item| !item.is_empty()).map(|item| {
+            let mut parts = item.splitn(2, '*');
+            let key = parts.next()?;
+            let weight = parts.next()?.parse::<f64>().ok()?;
+            if weight > 0.0 && weight.is_finite() {
+                Some((key, weight))
+            } else {
+                None
+            }
+        });
+        let mut total = 0.0;
+        let summary = match kind {
+            "td" => {
+                let compression = param.max(COMPRESSION_RANGE.0).min(COMPRESSION_RANGE.1);
+                let mut digest = TDigest::new(compression);
+                for item in items {
+                    let (mean, weight) = item?;
+                    let mean = mean.parse::<f64>().ok()?;
+                    if !mean.is_finite() {
+                        return None;
+                    }
+                    digest.insert(mean, weight);
+                    total += weight;
+                }
+                Summary::TDigest(digest)
+            }
+            // the buckets only mean the values of their own accuracy
+            "dd" if param >= ACCURACY_RANGE.0 && param <= ACCURACY_RANGE.1 => {
+                let mut sketch = DDSketch::new(param);
+                sketch.zero = zero;
+                total += zero;
+                for item in items {
+                    let (idx, weight) = item?;
+                    let (buckets, idx) = match idx.strip_prefix('n') {
+                        Some(idx) => (&mut sketch.negative, idx),
+                        None => (&mut sketch.positive, idx),
+                    };
+                    *buckets.entry(idx.parse().ok()?).or_insert(0.0) += weight;
+                    total += weight;
+                }
+                collapse(&mut sketch.positive);
+                collapse(&mut sketch.negative);
+                Summary::DDSketch(sketch)
+            }
+            _ => return None,
+        };
+        // the ranks are the weights of the points
+        if (total - len).abs() > 1e-6 * len.max(1.0) {
+            return None;
+        }
+        Some(Sketch {
+            count,
+            len,
+            sum,
+            min,
+            max,
+            summary,
+        })
+    }
+}
+
+fn points_of(summary: &Summary) -> Vec<(f64, f64)> {
+    match *summary {
+        Summary::TDigest(ref digest) => digest.points(),
+        Summary::DDSketch(ref sketch) => sketch.points(),
+    }
+}
+
+fn cmp_f64(a: &f64, b: &f64) -> Ordering {
+    a.partial_cmp(b).unwrap_or(Ordering::Equal)
+}
+
+/// TDigest is a merging t-digest, the inserts are buffered and merged
+/// into at most about `compression` centroids.
+#[derive(Clone, Debug)]
+pub struct TDigest {
+    compression: f64,
+    /// the sorted (mean, weight) centroids
+    centroids: Vec<(f64, f64)>,
+    buffer: Vec<(f64, f64)>,
+}
+
+impl TDigest {
+    pub fn new(compression: f64) -> TDigest {
+        TDigest {
+            compression,
+            centroids: Vec::new(),
+            buffer: Vec::new(),
+        }
+    }
+
+    pub fn insert(&mut self, value: f64, weight: f64) {
+        self.buffer.push((value, weight));
+        if self.buffer.len() as f64 >= self.compression * 5.0 {
+            self.compress();
+        }
+    }
+
+    pub fn merge(&mut self, other: TDigest) {
+        self.buffer.extend(other.centroids);
+        self.buffer.extend(other.buffer);
+        self.compress();
+    }
+
+    pub fn points(&self) -> Vec<(f64, f64)> {
+        let mut digest = self.clone();
+        digest.compress();
+        digest.centroids
+    }
+
+    /// merge the neighbour centroids while the k1 scale function allows,
+    /// which keeps the centroids at the tails small.
+    fn compress(&mut self) {
+        if self.buffer.is_empty() {
+            return;
+        }
+        let mut items = mem::take(&mut self.centroids);
+        items.append(&mut self.buffer);
+        items.sort_by(|a, b| cmp_f64(&a.0, &b.0));
+        let total: f64 = items.iter().map(|&(_, weight)| weight).sum();
+
+        let compression = self.compression;
+        let k = |q: f64| compression / (2.0 * PI) * (2.0 * q - 1.0).asin();
+        // k is at most compression / 4, where q is 1
+        let k_inv = |k: f64| if k >= compression / 4.0 {
+            1.0
+        } else {
+            ((k * 2.0 * PI / compression).sin() + 1.0) / 2.0
+        };
+
+        let mut centroids = Vec::with_capacity(compression as usize);
+        let mut iter = items.into_iter();
+        let mut current = iter.next().expect("never empty");
+        let mut done = 0.0;
+        let mut limit = total * k_inv(k(0.0) + 1.0);
+        for (mean, weight) in iter {
+            if done + current.1 + weight <= limit {
+                let merged = current.1 + weight;
+                current.0 += (mean - current.0) * weight / merged;
+                current.1 = merged;
+            } else {
+                done += current.1;
+                limit = total * k_inv(k(done / total) + 1.0);
+                centroids.push(current);
+                current = (mean, weight);
+            }
+        }
+        centroids.push(current);
+        self.centroids = centroids;
+    }
+}
+
+/// DDSketch keeps the counts in the logarithmic buckets, so a value is
+/// read back within the relative accuracy.
+#[derive(Clone, Debug)]
+pub struct DDSketch {
+    gamma: f64,
+    ln_gamma: f64,
+    positive: BTreeMap<i32, f64>,
+    negative: BTreeMap<i32, f64>,
+    zero: f64,
+}
+
+impl DDSketch {
+    pub fn new(accuracy: f64) -> DDSketch {
+        let gamma = (1.0 + accuracy) / (1.0 - accuracy);
+        DDSketch {
+            gamma,
+            ln_gamma: gamma.ln(),
+            positive: BTreeMap::new(),
+            negative: BTreeMap::new(),
+            zero: 0.0,
+        }
+    }
+
+    pub fn insert(&mut self, value: f64, weight: f64) {
+        if value.abs() < MIN_INDEXABLE {
+            self.zero += weight;
+            return;
+        }
+        let idx = (value.abs().ln() / self.ln_gamma).ceil() as i32;
+        let buckets = if value > 0.0 {
+            &mut self.positive
+        } else {
+            &mut self.negative
+        };
+        *buckets.entry(idx).or_insert(0.0) += weight;
+        collapse(buckets);
+    }
+
+    fn accuracy(&self) -> f64 {
+        (self.gamma - 1.0) / (self.gamma + 1.0)
+    }
+
+    pub fn merge(&mut self, other: &DDSketch) {
+        for (&idx, &weight) in &other.positive {
+            *self.positive.entry(idx).or_insert(0.0) += weight;
+        }
+        for (&idx, &weight) in &other.negative {
+            *self.negative.entry(idx).or_insert(0.0) += weight;
+        }
+        self.zero += other.zero;
+        collapse(&mut self.positive);
+        collapse(&mut self.negative);
+    }
+
+    /// the value of a bucket, within the accuracy of all the values in it.
+    fn value(&self, idx: i32) -> f64 {
+        2.0 * self.gamma.powi(idx) / (self.gamma + 1.0)
+    }
+
+    pub fn points(&self) -> Vec<(f64, f64)> {
+        let mut points: Vec<_> = self.negative
+            .iter()
+            .rev()
+            .map(|(&idx, &weight)| (-self.value(idx), weight))
+            .collect();
+        if self.zero > 0.0 {
+            points.push((0.0, self.zero));
+        }
+        points.extend(self.positive.iter().map(|(&idx, &weight)| (self.value(idx), weight)));
+        points
+    }
+}
+
+/// fold the lowest buckets into one to keep at most MAX_BUCKETS.
+fn collapse(buckets: &mut BTreeMap<i32, f64>) {
+    while buckets.len() > MAX_BUCKETS {
+        let (&low, &weight) = buckets.iter().next().expect("never empty");
+        buckets.remove(&low);
+        *buckets.iter_mut().next().expect("never empty").1 += weight;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn relative_error(sketch: &Sketch, rank: f64, exact: f64) -> f64 {
+        (sketch.value_at(rank) - exact).abs() / exact
+    }
+
+    #[test]
+    fn test_sketch_percentiles_within_bound() {
+        for &mode in &[TimerMode::TDigest(100.0), TimerMode::DDSketch(0.01)] {
+            let (mut a, mut b) = (Sketch::new(mode).unwrap(), Sketch::new(mode).unwrap());
+            // 1..=100000 shuffled by a multiplicative step
+            for num in 0..100_000u64 {
+                let value = (num * 7919 % 100_000 + 1) as f64;
+                if num % 2 == 0 {
+                    a.insert(value, 1.0, 1.0);
+                } else {
+                    b.insert(value, 2.0, 1.0);
+                }
+            }
+            a.merge(b);
+            assert_eq!((a.len, a.count, a.min, a.max), (100_000.0, 150_000.0, 1.0, 100_000.0));
+            assert_eq!(a.sum, 5_000_050_000.0);
+            for &rank in &[50_000.0, 90_000.0, 99_000.0, 99_900.0] {
+                let error = relative_error(&a, rank, rank);
+                assert!(error < 0.02, "{:?} rank {} error {}", mode, rank, error);
+            }
+            let sum_90 = a.sum_between(0.0, 90_000.0);
+            let exact = 90_000.0 * 90_001.0 / 2.0;
+            assert!((sum_90 - exact).abs() / exact < 0.02, "{:?} sum {}", mode, sum_90);
+        }
+    }
+
+    #[test]
+    fn test_encode_self_contained_parts() {
+        for &mode in &[TimerMode::TDigest(100.0), TimerMode::DDSketch(0.01)] {
+            let mut sketch = Sketch::new(mode).unwrap();
+            for num in 0..10_000 {
+                sketch.insert((num % 2000) as f64 - 500.0, 2.0, 1.0);
+            }
+            let lines = sketch.encode(1432);
+            assert!(lines.len() > 1, "{:?} in {} line", mode, lines.len());
+            let kind = if let TimerMode::TDigest(_) = mode { "td" } else { "dd" };
+            let parts: Vec<_> = lines.iter()
+                .map(|line| {
+                    let payload = line.trim_end_matches(&format!("|{}", kind)[..]);
+                    Sketch::decode(kind, payload).unwrap()
+                })
+                .collect();
+            let mut merged = Sketch::new(mode).unwrap();
+            for part in &parts {
+                assert!(part.min <= part.max && part.min.is_finite() && part.max.is_finite());
+                assert!(part.len > 0.0 && part.count > 0.0);
+                merged.merge(part.clone());
+            }
+            assert_eq!((merged.min, merged.max), (-500.0, 1499.0));
+            assert!((merged.len - 10_000.0).abs() < 1e-6);
+            assert!((merged.count - 20_000.0).abs() < 1e-6);
+            assert!((merged.sum - sketch.sum).abs() < 1e-6 * sketch.sum.abs());
+            // without the first part the rest is still a sketch of its points
+            let mut rest = parts[1].clone();
+            for part in &parts[2..] {
+                rest.merge(part.clone());
+            }
+            assert!(rest.min >= -500.0 && rest.min <= rest.max && rest.max <= 1499.0);
+            assert!(rest.len < 10_000.0 && rest.count < 20_000.0);
+            assert!(rest.value_at(rest.len) <= rest.max && rest.value_at(1.0) >= rest.min);
+        }
+    }
+
+    #[test]
+    fn test_decode_reject_hostile_payload() {
+        let digest = Sketch::decode("td", "1;1;5;5;5;1e18;0;5*1").unwrap();
+        match digest.summary {
+            Summary::TDigest(ref digest) => assert_eq!(digest.compression, COMPRESSION_RANGE.1),
+            _ => unreachable!(),
+        }
+        for &(kind, payload) in &[("td", "NaN;1;5;5;5;100;0;5*1"),
+                                  ("td", "1;1;inf;5;5;100;0;5*1"),
+                                  ("td", "-1;1;5;5;5;100;0;5*1"),
+                                  ("td", "1;1;5;5;5;100;0;5*-1"),
+                                  ("td", "1;1;5;5;5;100;0;5*0"),
+                                  ("td", "1;3;5;5;5;100;0;5*1"),
+                                  ("td", "1;1;5;6;5;100;0;5*1"),
+                                  ("td", "1;1;5;5;5;100;0;NaN*1"),
+                                  ("dd", "1;1;5;5;5;1e-300;0;1*1"),
+                                  ("dd", "1;1;5;5;5;0.01;-1;1*1")] {
+            assert!(Sketch::decode(kind, payload).is_none(), "{}", payload);
+        }
+    }
+
+    #[test]
+    fn test_sketch_bounded_size() {
+        let mut digest = TDigest::new(100.0);
+        let mut sketch = DDSketch::new(0.01);
+        for num in 0..1_000_000 {
+            digest.insert(num as f64, 1.0);
+            sketch.insert(num as f64 * 1e-3 - 10.0, 1.0);
+        }
+        assert!(digest.points().len() < 200, "{} centroids", digest.points().len());
+        assert!(sketch.points().len() <= MAX_BUCKETS * 2 + 1);
+    }
+}